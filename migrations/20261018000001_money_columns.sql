@@ -0,0 +1,9 @@
+-- Store money as NUMERIC with an ISO 4217 currency code instead of free-form text
+ALTER TABLE tickets
+    ALTER COLUMN price TYPE NUMERIC(10, 2) USING price::NUMERIC(10, 2),
+    ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'INR';
+
+ALTER TABLE bookings
+    ALTER COLUMN quantity TYPE INT USING quantity::INT,
+    ALTER COLUMN total_price TYPE NUMERIC(12, 2) USING total_price::NUMERIC(12, 2),
+    ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'INR';
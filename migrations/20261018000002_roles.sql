@@ -0,0 +1,19 @@
+-- Account-wide roles
+ALTER TABLE users
+    ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'attendee'
+    CHECK (role IN ('attendee', 'organizer', 'scanner', 'admin'));
+
+-- Per-event rights: the owner, co-organizers and door scanners
+CREATE TABLE event_members (
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    role VARCHAR(20) NOT NULL CHECK (role IN ('owner', 'co_organizer', 'scanner')),
+    added_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (event_id, user_id)
+);
+
+INSERT INTO event_members (event_id, user_id, role)
+SELECT event_id, user_id, 'owner' FROM events WHERE user_id IS NOT NULL;
+
+UPDATE users SET role = 'organizer'
+WHERE role = 'attendee' AND user_id IN (SELECT user_id FROM events);
@@ -0,0 +1,14 @@
+-- Audit trail of login lockouts triggered by repeated failed attempts
+CREATE TABLE login_lockouts (
+    lockout_id UUID PRIMARY KEY,
+    -- "account" (subject is the email tried) or "ip" (subject is the address)
+    scope VARCHAR(10) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    user_id UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    ip_address VARCHAR(64),
+    failures INTEGER NOT NULL,
+    locked_until TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX login_lockouts_created_at_idx ON login_lockouts (created_at);
@@ -0,0 +1,17 @@
+-- Event-scoped API keys for scanner devices and integrations
+CREATE TABLE api_keys (
+    key_id UUID PRIMARY KEY,
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    created_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    name VARCHAR(100) NOT NULL,
+    -- Leading characters of the key, shown in listings so keys can be told apart
+    prefix VARCHAR(16) NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    permissions TEXT[] NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX api_keys_event_id_idx ON api_keys (event_id);
@@ -0,0 +1,21 @@
+-- When and by which scanner device a booking was checked in. A NULL device
+-- means an online check-in.
+ALTER TABLE bookings
+    ADD COLUMN checked_in_at TIMESTAMP,
+    ADD COLUMN checked_in_device VARCHAR(100);
+
+-- Scans recorded by scanner devices while offline and uploaded later. The
+-- earliest scan of a booking wins; later ones are reported back as conflicts.
+CREATE TABLE offline_scans (
+    scan_id UUID PRIMARY KEY,
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    booking_id UUID NOT NULL REFERENCES bookings(booking_id) ON DELETE CASCADE,
+    device_id VARCHAR(100) NOT NULL,
+    -- Device clock at the time of the scan
+    scanned_at TIMESTAMP NOT NULL,
+    received_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- Uploading the same scan again is a no-op
+    UNIQUE (device_id, booking_id, scanned_at)
+);
+
+CREATE INDEX offline_scans_event_device_idx ON offline_scans (event_id, device_id);
@@ -0,0 +1,36 @@
+-- One admission per person on a booking, each with its own credential and
+-- check-in state
+CREATE TABLE admissions (
+    admission_id UUID PRIMARY KEY,
+    booking_id UUID NOT NULL REFERENCES bookings(booking_id) ON DELETE CASCADE,
+    -- 1 to the booking's quantity
+    seq INT NOT NULL,
+    holder_name VARCHAR(200),
+    verified BOOLEAN NOT NULL DEFAULT FALSE,
+    checked_in_at TIMESTAMP,
+    -- Scanner device that checked the admission in; NULL when done online
+    checked_in_device VARCHAR(100),
+    UNIQUE (booking_id, seq)
+);
+
+-- Existing bookings get an admission per unit of quantity. A booking that was
+-- checked in admitted its whole group, so each admission keeps that state.
+INSERT INTO admissions (admission_id, booking_id, seq, verified, checked_in_at, checked_in_device)
+SELECT gen_random_uuid(), b.booking_id, n, b.verified, b.checked_in_at, b.checked_in_device
+FROM bookings b, generate_series(1, b.quantity) AS n;
+
+-- Offline scans now resolve per admission
+ALTER TABLE offline_scans
+    ADD COLUMN admission_id UUID REFERENCES admissions(admission_id) ON DELETE CASCADE;
+UPDATE offline_scans s SET admission_id = a.admission_id
+FROM admissions a
+WHERE a.booking_id = s.booking_id AND a.seq = 1;
+ALTER TABLE offline_scans
+    ALTER COLUMN admission_id SET NOT NULL,
+    DROP CONSTRAINT offline_scans_device_id_booking_id_scanned_at_key,
+    ADD UNIQUE (device_id, admission_id, scanned_at);
+
+-- bookings.verified stays, meaning every admission is checked in
+ALTER TABLE bookings
+    DROP COLUMN checked_in_at,
+    DROP COLUMN checked_in_device;
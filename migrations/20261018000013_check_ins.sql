@@ -0,0 +1,48 @@
+-- Whether the person is currently inside, and how many times they have entered
+ALTER TABLE admissions
+    ADD COLUMN inside BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN entries INT NOT NULL DEFAULT 0;
+UPDATE admissions SET inside = verified, entries = CASE WHEN verified THEN 1 ELSE 0 END;
+
+-- How often an admission may be used to enter an event. Events without a
+-- row allow a single entry.
+CREATE TABLE event_entry_policies (
+    event_id UUID PRIMARY KEY REFERENCES events(event_id) ON DELETE CASCADE,
+    reentry_allowed BOOLEAN NOT NULL DEFAULT FALSE,
+    -- NULL for no limit
+    max_entries INT CHECK (max_entries > 0),
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Every scan at the door, including the ones turned away
+CREATE TABLE check_ins (
+    check_in_id UUID PRIMARY KEY,
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    booking_id UUID NOT NULL REFERENCES bookings(booking_id) ON DELETE CASCADE,
+    -- NULL when no admission on the booking could be scanned
+    admission_id UUID REFERENCES admissions(admission_id) ON DELETE CASCADE,
+    direction VARCHAR(3) NOT NULL CHECK (direction IN ('in', 'out')),
+    accepted BOOLEAN NOT NULL,
+    -- Why a scan was turned away, e.g. "reentry_denied"
+    reason VARCHAR(50),
+    gate VARCHAR(100),
+    -- Who scanned: a signed-in user or an event API key, and the device used
+    scanned_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    api_key_id UUID REFERENCES api_keys(key_id) ON DELETE SET NULL,
+    device_id VARCHAR(100),
+    scanned_at TIMESTAMP NOT NULL,
+    recorded_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX check_ins_booking_idx ON check_ins (booking_id, scanned_at);
+CREATE INDEX check_ins_event_idx ON check_ins (event_id, scanned_at);
+
+-- Check-ins made before scans were recorded
+INSERT INTO check_ins
+    (check_in_id, event_id, booking_id, admission_id, direction, accepted, device_id, scanned_at)
+SELECT gen_random_uuid(), t.event_id, a.booking_id, a.admission_id, 'in', TRUE,
+    a.checked_in_device, COALESCE(a.checked_in_at, b.booking_date, NOW())
+FROM admissions a
+JOIN bookings b ON b.booking_id = a.booking_id
+JOIN tickets t ON t.ticket_id = b.ticket_id
+WHERE a.verified AND t.event_id IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Position of a scan in the event's live feed, so dashboards can resume
+ALTER TABLE check_ins ADD COLUMN seq BIGSERIAL NOT NULL;
+CREATE UNIQUE INDEX check_ins_seq_idx ON check_ins (seq);
+CREATE INDEX check_ins_event_seq_idx ON check_ins (event_id, seq);
+
+-- Tell listeners about each scan once it is committed
+CREATE FUNCTION notify_check_in() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('check_ins', NEW.seq::TEXT);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER check_ins_notify
+    AFTER INSERT ON check_ins
+    FOR EACH ROW EXECUTE FUNCTION notify_check_in();
@@ -0,0 +1,15 @@
+-- Audit trail of edits to events, one row per field changed. Fields changed
+-- in the same edit share an update_id.
+CREATE TABLE event_changes (
+    change_id UUID PRIMARY KEY,
+    update_id UUID NOT NULL,
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    changed_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    field VARCHAR(50) NOT NULL,
+    -- Values as text; NULL when the field was or became empty
+    old_value TEXT,
+    new_value TEXT,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX event_changes_event_idx ON event_changes (event_id, changed_at);
@@ -0,0 +1,10 @@
+-- Replace the "has happened" flag with a lifecycle status. Events created
+-- before this were public straight away, so they count as published.
+ALTER TABLE events ALTER COLUMN event_status DROP DEFAULT;
+ALTER TABLE events ALTER COLUMN event_status TYPE VARCHAR(20)
+    USING CASE WHEN event_status THEN 'completed' ELSE 'published' END;
+ALTER TABLE events ALTER COLUMN event_status SET DEFAULT 'draft';
+ALTER TABLE events ADD CONSTRAINT events_event_status_check CHECK (event_status IN
+    ('draft', 'published', 'sales_paused', 'cancelled', 'postponed', 'completed'));
+
+CREATE INDEX events_event_status_idx ON events (event_status);
@@ -0,0 +1,21 @@
+-- When an event runs, as instants, plus the IANA timezone it is held in.
+-- event_date stays as the local date the event starts on.
+ALTER TABLE events
+    ADD COLUMN starts_at TIMESTAMPTZ,
+    ADD COLUMN ends_at TIMESTAMPTZ,
+    ADD COLUMN doors_open_at TIMESTAMPTZ,
+    ADD COLUMN timezone VARCHAR(64) NOT NULL DEFAULT 'UTC';
+
+-- Events made before this only had a date, so they take up the whole day
+UPDATE events SET
+    starts_at = event_date::TIMESTAMP AT TIME ZONE 'UTC',
+    ends_at = (event_date + 1)::TIMESTAMP AT TIME ZONE 'UTC';
+
+ALTER TABLE events
+    ALTER COLUMN starts_at SET NOT NULL,
+    ALTER COLUMN ends_at SET NOT NULL,
+    ADD CONSTRAINT events_times_check CHECK (
+        ends_at > starts_at AND (doors_open_at IS NULL OR doors_open_at <= starts_at)
+    );
+
+CREATE INDEX events_ends_at_idx ON events (ends_at);
@@ -0,0 +1,12 @@
+-- What kind of event it is, for filtering the catalog, and when it was
+-- created, for listing the newest first
+ALTER TABLE events
+    ADD COLUMN category VARCHAR(50),
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX events_starts_at_idx ON events (starts_at, event_id);
+CREATE INDEX events_created_at_idx ON events (created_at, event_id);
+CREATE INDEX events_category_idx ON events (LOWER(category));
+CREATE INDEX events_user_id_idx ON events (user_id, starts_at, event_id);
+CREATE INDEX tickets_event_id_idx ON tickets (event_id);
+CREATE INDEX bookings_user_id_idx ON bookings (user_id, booking_date, booking_id);
@@ -0,0 +1,26 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- What event search matches against. The name weighs most, then the venue,
+-- then the description. Being immutable it can back an index, which
+-- Postgres keeps up to date as events are added and edited.
+CREATE FUNCTION event_search_document(
+    event_name TEXT,
+    event_location TEXT,
+    event_description TEXT
+) RETURNS TSVECTOR
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE
+RETURN setweight(to_tsvector('english'::REGCONFIG, COALESCE(event_name, '')), 'A')
+    || setweight(to_tsvector('english'::REGCONFIG, COALESCE(event_location, '')), 'B')
+    || setweight(to_tsvector('english'::REGCONFIG, COALESCE(event_description, '')), 'C');
+
+CREATE INDEX events_search_idx ON events
+    USING GIN (event_search_document(event_name, event_location, event_description));
+
+-- For searches with typos, which full-text matching misses
+CREATE INDEX events_name_trgm_idx ON events USING GIN (event_name gin_trgm_ops);
+CREATE INDEX events_location_trgm_idx ON events USING GIN (event_location gin_trgm_ops);
+
+-- Search snippets are HTML with <mark>ed matches, so the text around them is escaped
+CREATE FUNCTION html_escape(text TEXT) RETURNS TEXT
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE
+RETURN replace(replace(replace(replace(text, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;');
@@ -0,0 +1,10 @@
+-- Scan manifests handed out to scanners. Offline scans name the manifest
+-- they were checked against, and can't predate it.
+CREATE TABLE scan_manifests (
+    manifest_id UUID PRIMARY KEY,
+    event_id UUID NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    issued_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX scan_manifests_event_id_idx ON scan_manifests (event_id);
@@ -0,0 +1,180 @@
+use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::{dev::Payload, web, Error as ActixWebError};
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::Postgres, Pool};
+use std::future::ready;
+use uuid::Uuid;
+
+use crate::jwt_auth::{ErrorResponse, JwtMiddleware};
+use crate::models::AppState;
+use crate::refresh_token::hash_token;
+use crate::roles::{forbidden, require_event_role, EventRole};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+// What an API key may do on its event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiPermission {
+    // Check attendees in at the door
+    Verify,
+}
+
+impl ApiPermission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiPermission::Verify => "verify",
+        }
+    }
+
+    pub fn parse(permission: &str) -> Option<Self> {
+        match permission {
+            "verify" => Some(ApiPermission::Verify),
+            _ => None,
+        }
+    }
+}
+
+/// Scope strings as shown to users, e.g. `verify:event/<id>`.
+pub fn scopes(permissions: &[String], event_id: Uuid) -> Vec<String> {
+    permissions
+        .iter()
+        .map(|permission| format!("{}:event/{}", permission, event_id))
+        .collect()
+}
+
+/// A new random key and the prefix shown for it. Only the key's hash is stored.
+pub fn generate_key() -> (String, String) {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = hex::encode(bytes);
+    let prefix = format!("kp_{}", &secret[..8]);
+    (format!("{}_{}", prefix, &secret[8..]), prefix)
+}
+
+// An API key that authenticated the request
+pub struct ApiKeyAuth {
+    pub key_id: Uuid,
+    pub event_id: Uuid,
+    pub permissions: Vec<String>,
+}
+
+impl ApiKeyAuth {
+    pub fn allows(&self, permission: ApiPermission, event_id: Uuid) -> bool {
+        self.event_id == event_id && self.permissions.iter().any(|p| p == permission.as_str())
+    }
+}
+
+impl FromRequest for ApiKeyAuth {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
+        let presented = match req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(key) => key.to_string(),
+            None => {
+                let json_error = ErrorResponse {
+                    status: "fail".to_string(),
+                    message: "API key not found in headers".to_string(),
+                };
+                return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
+            }
+        };
+
+        Box::pin(async move {
+            let key = sqlx::query!(
+                "SELECT key_id, event_id, permissions, last_used_at < NOW() - INTERVAL '1 minute' AS stale
+                FROM api_keys
+                WHERE key_hash = $1 AND revoked_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > NOW())",
+                hash_token(&presented)
+            )
+            .fetch_optional(&data.db)
+            .await;
+
+            match key {
+                Ok(Some(key)) => {
+                    // Keep last_used_at roughly current without a write on every scan
+                    if key.stale != Some(false) {
+                        if let Err(err) = sqlx::query!(
+                            "UPDATE api_keys SET last_used_at = NOW() WHERE key_id = $1",
+                            key.key_id
+                        )
+                        .execute(&data.db)
+                        .await
+                        {
+                            log::warn!("Failed to update API key {}: {}", key.key_id, err);
+                        }
+                    }
+                    Ok(ApiKeyAuth {
+                        key_id: key.key_id,
+                        event_id: key.event_id,
+                        permissions: key.permissions,
+                    })
+                }
+                Ok(None) => {
+                    let json_error = ErrorResponse {
+                        status: "fail".to_string(),
+                        message: "Invalid, expired or revoked API key".to_string(),
+                    };
+                    Err(ErrorUnauthorized(json_error))
+                }
+                Err(_) => {
+                    let json_error = ErrorResponse {
+                        status: "error".to_string(),
+                        message: "Failed to check API key".to_string(),
+                    };
+                    Err(ErrorInternalServerError(json_error))
+                }
+            }
+        })
+    }
+}
+
+/// Who is calling an endpoint that both users and API keys may use. A request
+/// carrying an X-Api-Key header is authenticated by that key alone.
+pub enum Caller {
+    User(JwtMiddleware),
+    ApiKey(ApiKeyAuth),
+}
+
+impl FromRequest for Caller {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if req.headers().contains_key(API_KEY_HEADER) {
+            let key = ApiKeyAuth::from_request(req, payload);
+            Box::pin(async move { Ok(Caller::ApiKey(key.await?)) })
+        } else {
+            let jwt_guard = JwtMiddleware::from_request(req, payload);
+            Box::pin(async move { Ok(Caller::User(jwt_guard.await?)) })
+        }
+    }
+}
+
+impl Caller {
+    /// Checks the caller may act on the event: users by their event role,
+    /// API keys by their permissions.
+    pub async fn require(
+        &self,
+        db: &Pool<Postgres>,
+        event_id: Uuid,
+        roles: &[EventRole],
+        permission: ApiPermission,
+    ) -> Result<(), HttpResponse> {
+        match self {
+            Caller::User(jwt_guard) => {
+                require_event_role(db, &jwt_guard.user, event_id, roles).await
+            }
+            Caller::ApiKey(key) if key.allows(permission, event_id) => Ok(()),
+            Caller::ApiKey(_) => Err(forbidden("This API key is not allowed to do that")),
+        }
+    }
+}
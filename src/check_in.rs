@@ -0,0 +1,641 @@
+use chrono::{Duration, NaiveDateTime, SubsecRound, Utc};
+use core::fmt;
+use serde::Serialize;
+use sqlx::{postgres::Postgres, Pool};
+use uuid::Uuid;
+
+use crate::api_key::Caller;
+use crate::keyring::Keyring;
+use crate::models::{Admission, Direction, EntryPolicy, OfflineScan};
+use crate::ticket_credential::{verify_ticket_credential, TicketCredential};
+
+// Most scans a device may upload at once
+pub const MAX_SCANS_PER_UPLOAD: usize = 1000;
+// How far a scanner's clock may be off from ours
+const CLOCK_SKEW_SECONDS: i64 = 120;
+
+#[derive(Debug)]
+pub enum ScanError {
+    // Bad signature, expired, or not a ticket credential at all
+    InvalidCredential,
+    WrongEvent,
+    // The booking was cancelled or no longer matches the credential
+    Superseded,
+    Database(sqlx::Error),
+}
+
+impl ScanError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScanError::InvalidCredential => "invalid_credential",
+            ScanError::WrongEvent => "wrong_event",
+            ScanError::Superseded => "credential_superseded",
+            ScanError::Database(_) => "database_error",
+        }
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::InvalidCredential => write!(f, "Ticket credential is invalid or expired"),
+            ScanError::WrongEvent => write!(f, "Ticket is for a different event"),
+            ScanError::Superseded => write!(f, "Ticket no longer matches its booking"),
+            ScanError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ScanError {
+    fn from(err: sqlx::Error) -> Self {
+        ScanError::Database(err)
+    }
+}
+
+/// Checks a scanned credential's signature, that it is for `event_id`, and
+/// that its admission still exists on a booking with the same holder.
+pub async fn validate_credential<'e, E>(
+    executor: E,
+    keyring: &Keyring,
+    event_id: Uuid,
+    token: &str,
+) -> Result<TicketCredential, ScanError>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    let credential = verify_ticket_credential(keyring, token.trim())
+        .map_err(|_| ScanError::InvalidCredential)?;
+    if credential.event_id != event_id {
+        return Err(ScanError::WrongEvent);
+    }
+
+    let booking = sqlx::query!(
+        "SELECT b.user_id, t.event_id AS \"event_id?\"
+        FROM admissions a
+        JOIN bookings b ON b.booking_id = a.booking_id
+        LEFT JOIN tickets t ON t.ticket_id = b.ticket_id
+        WHERE a.admission_id = $1 AND a.booking_id = $2",
+        credential.admission_id,
+        credential.booking_id
+    )
+    .fetch_optional(executor)
+    .await?;
+    match booking {
+        Some(booking)
+            if booking.user_id == Some(credential.holder) && booking.event_id == Some(event_id) =>
+        {
+            Ok(credential)
+        }
+        _ => Err(ScanError::Superseded),
+    }
+}
+
+// Marks the booking verified once every admission on it is checked in
+async fn refresh_booking_verified<'e, E>(executor: E, booking_id: Uuid) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    sqlx::query!(
+        "UPDATE bookings SET verified = NOT EXISTS (
+            SELECT 1 FROM admissions WHERE booking_id = $1 AND NOT verified
+        )
+        WHERE booking_id = $1",
+        booking_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// Who made a scan and at which gate
+#[derive(Debug, Default)]
+pub struct Scanner {
+    pub user_id: Option<Uuid>,
+    pub api_key_id: Option<Uuid>,
+    pub gate: Option<String>,
+}
+
+impl Scanner {
+    pub fn new(caller: &Caller, gate: Option<&str>) -> Self {
+        let (user_id, api_key_id) = match caller {
+            Caller::User(jwt_guard) => (Some(jwt_guard.user.user_id), None),
+            Caller::ApiKey(key) => (None, Some(key.key_id)),
+        };
+        Scanner {
+            user_id,
+            api_key_id,
+            gate: gate
+                .map(str::trim)
+                .filter(|gate| !gate.is_empty())
+                .map(str::to_string),
+        }
+    }
+}
+
+// Why a scan was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    AlreadyInside,
+    ReentryDenied,
+    MaxEntriesReached,
+    NotInside,
+    // Scanned again offline after an earlier scan already let the person in
+    DuplicateScan,
+}
+
+impl DenyReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DenyReason::AlreadyInside => "already_inside",
+            DenyReason::ReentryDenied => "reentry_denied",
+            DenyReason::MaxEntriesReached => "max_entries_reached",
+            DenyReason::NotInside => "not_inside",
+            DenyReason::DuplicateScan => "duplicate_scan",
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyReason::AlreadyInside => write!(f, "Already scanned in"),
+            DenyReason::ReentryDenied => write!(f, "Re-entry is not allowed for this event"),
+            DenyReason::MaxEntriesReached => write!(f, "No entries left on this ticket"),
+            DenyReason::NotInside => write!(f, "Not scanned in, so can't be scanned out"),
+            DenyReason::DuplicateScan => write!(f, "Already scanned"),
+        }
+    }
+}
+
+pub enum ScanOutcome {
+    Admitted(Admission),
+    Denied(DenyReason),
+}
+
+/// The event's entry policy, or single entry if it has none.
+pub async fn entry_policy<'e, E>(executor: E, event_id: Uuid) -> Result<EntryPolicy, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    let policy = sqlx::query_as!(
+        EntryPolicy,
+        "SELECT reentry_allowed, max_entries FROM event_entry_policies WHERE event_id = $1",
+        event_id
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(policy.unwrap_or(EntryPolicy {
+        reentry_allowed: false,
+        max_entries: None,
+    }))
+}
+
+// A scan as written to the check-in history
+struct ScanRecord<'a> {
+    event_id: Uuid,
+    booking_id: Uuid,
+    admission_id: Option<Uuid>,
+    direction: Direction,
+    denied: Option<DenyReason>,
+    scanner: &'a Scanner,
+    device_id: Option<&'a str>,
+    scanned_at: NaiveDateTime,
+}
+
+async fn record_check_in<'e, E>(executor: E, scan: ScanRecord<'_>) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    sqlx::query!(
+        "INSERT INTO check_ins
+            (check_in_id, event_id, booking_id, admission_id, direction, accepted, reason,
+            gate, scanned_by, api_key_id, device_id, scanned_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+        Uuid::new_v4(),
+        scan.event_id,
+        scan.booking_id,
+        scan.admission_id,
+        match scan.direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        },
+        scan.denied.is_none(),
+        scan.denied.map(|reason| reason.code()),
+        scan.scanner.gate,
+        scan.scanner.user_id,
+        scan.scanner.api_key_id,
+        scan.device_id,
+        scan.scanned_at
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Scans an admission on `booking_id` in or out, applying the event's entry
+/// policy. When `admission_id` is None, takes the next admission on the
+/// booking that can go that way. Every scan is recorded, allowed or not.
+pub async fn admit(
+    db: &Pool<Postgres>,
+    event_id: Uuid,
+    booking_id: Uuid,
+    admission_id: Option<Uuid>,
+    direction: Direction,
+    scanner: &Scanner,
+    device_id: Option<&str>,
+) -> Result<ScanOutcome, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let policy = entry_policy(&mut *tx, event_id).await?;
+
+    let admission = match admission_id {
+        Some(admission_id) => {
+            sqlx::query_as!(
+                Admission,
+                "SELECT * FROM admissions WHERE admission_id = $1 AND booking_id = $2 FOR UPDATE",
+                admission_id,
+                booking_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        // People going in are taken before those already in, fewest entries
+        // first. SKIP LOCKED so two doors scanning the same group take
+        // different admissions.
+        None => {
+            sqlx::query_as!(
+                Admission,
+                "SELECT * FROM admissions
+                WHERE booking_id = $1
+                ORDER BY inside = $2, entries, seq
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED",
+                booking_id,
+                direction == Direction::In
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+    };
+
+    let scanned_admission = admission.as_ref().map(|admission| admission.admission_id);
+    let allowed = match admission {
+        None if direction == Direction::In => Err(DenyReason::AlreadyInside),
+        None => Err(DenyReason::NotInside),
+        Some(admission) => match direction {
+            Direction::In if admission.inside => Err(DenyReason::AlreadyInside),
+            Direction::In if admission.entries > 0 && !policy.reentry_allowed => {
+                Err(DenyReason::ReentryDenied)
+            }
+            Direction::In
+                if policy
+                    .max_entries
+                    .is_some_and(|max| admission.entries >= max) =>
+            {
+                Err(DenyReason::MaxEntriesReached)
+            }
+            Direction::Out if !admission.inside => Err(DenyReason::NotInside),
+            _ => Ok(admission),
+        },
+    };
+    record_check_in(
+        &mut *tx,
+        ScanRecord {
+            event_id,
+            booking_id,
+            admission_id: scanned_admission,
+            direction,
+            denied: allowed.as_ref().err().copied(),
+            scanner,
+            device_id,
+            scanned_at: Utc::now().naive_utc(),
+        },
+    )
+    .await?;
+
+    let outcome = match allowed {
+        Err(reason) => ScanOutcome::Denied(reason),
+        Ok(admission) => {
+            let admission = sqlx::query_as!(
+                Admission,
+                "UPDATE admissions SET
+                    inside = $2,
+                    entries = entries + $2::BOOLEAN::INT,
+                    verified = verified OR $2,
+                    checked_in_at = CASE WHEN $2 THEN COALESCE(checked_in_at, NOW())
+                        ELSE checked_in_at END
+                WHERE admission_id = $1
+                RETURNING *",
+                admission.admission_id,
+                direction == Direction::In
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            refresh_booking_verified(&mut *tx, booking_id).await?;
+            ScanOutcome::Admitted(admission)
+        }
+    };
+    tx.commit().await?;
+    Ok(outcome)
+}
+
+/// How many of a booking's admissions are checked in, and how many it has.
+pub async fn booking_progress(
+    db: &Pool<Postgres>,
+    booking_id: Uuid,
+) -> Result<(i64, i64), sqlx::Error> {
+    let progress = sqlx::query!(
+        "SELECT COUNT(*) FILTER (WHERE verified) AS \"checked_in!\", COUNT(*) AS \"quantity!\"
+        FROM admissions WHERE booking_id = $1",
+        booking_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok((progress.checked_in, progress.quantity))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    // This scan is the one the admission's check-in is credited to
+    Accepted,
+    // The admission was checked in by an earlier scan
+    Conflict,
+    Rejected,
+}
+
+// The scan an admission's check-in is credited to
+#[derive(Debug, Serialize)]
+pub struct ScanWinner {
+    // None for an online check-in
+    pub device_id: Option<String>,
+    // None for check-ins made before check-in times were recorded
+    pub scanned_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScanResult {
+    // Position of the scan in the upload
+    pub index: usize,
+    pub booking_id: Option<Uuid>,
+    pub admission_id: Option<Uuid>,
+    pub scanned_at: NaiveDateTime,
+    pub status: ScanStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub winner: Option<ScanWinner>,
+}
+
+// A scan by the device that lost to an earlier one, possibly from another device
+#[derive(Debug, Serialize)]
+pub struct ScanConflict {
+    pub booking_id: Uuid,
+    pub admission_id: Uuid,
+    pub scanned_at: NaiveDateTime,
+    pub winner: ScanWinner,
+}
+
+// Whether a scan came before the admission's current check-in. Ties go to the
+// lower device id, with online check-ins ahead of every device.
+fn scanned_first(
+    scanned_at: NaiveDateTime,
+    device_id: &str,
+    verified: bool,
+    checked_in_at: Option<NaiveDateTime>,
+    checked_in_device: Option<&str>,
+) -> bool {
+    if !verified {
+        return true;
+    }
+    match checked_in_at {
+        Some(checked_in_at) => (scanned_at, Some(device_id)) < (checked_in_at, checked_in_device),
+        None => false,
+    }
+}
+
+/// When a device's offline scans can have happened: after it was handed its
+/// manifest, while the event's doors were open, and not later than now.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanWindow {
+    manifest_issued_at: NaiveDateTime,
+    opens_at: NaiveDateTime,
+    ends_at: NaiveDateTime,
+}
+
+impl ScanWindow {
+    // Why a scan can't have happened when its device says, allowing for the
+    // device's clock being off a little either way
+    fn check(&self, scanned_at: NaiveDateTime, now: NaiveDateTime) -> Result<(), &'static str> {
+        let skew = Duration::seconds(CLOCK_SKEW_SECONDS);
+        let after = |at: NaiveDateTime| scanned_at.signed_duration_since(at) > skew;
+        let before = |at: NaiveDateTime| at.signed_duration_since(scanned_at) > skew;
+        if after(now) {
+            Err("scanned_in_future")
+        } else if before(self.manifest_issued_at) {
+            Err("scanned_before_manifest")
+        } else if before(self.opens_at) || after(self.ends_at) {
+            Err("outside_event_window")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The window for scans checked against `manifest_id`, if that manifest was
+/// issued for `event_id`.
+pub async fn scan_window(
+    db: &Pool<Postgres>,
+    event_id: Uuid,
+    manifest_id: Uuid,
+) -> Result<Option<ScanWindow>, sqlx::Error> {
+    let window = sqlx::query!(
+        "SELECT m.issued_at, COALESCE(e.doors_open_at, e.starts_at) AS \"opens_at!\", e.ends_at
+        FROM scan_manifests m
+        JOIN events e ON e.event_id = m.event_id
+        WHERE m.manifest_id = $1 AND m.event_id = $2",
+        manifest_id,
+        event_id
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|row| ScanWindow {
+        manifest_issued_at: row.issued_at,
+        opens_at: row.opens_at.naive_utc(),
+        ends_at: row.ends_at.naive_utc(),
+    });
+    Ok(window)
+}
+
+/// Applies scans a device recorded offline. The earliest scan of each admission
+/// wins, whichever device made it and whenever it was uploaded, so every
+/// device ends up with the same answer. Besides a result per uploaded scan,
+/// returns every scan by this device that has since lost to an earlier one.
+/// Scans dated outside `window` are rejected.
+pub async fn sync_offline_scans(
+    db: &Pool<Postgres>,
+    keyring: &Keyring,
+    event_id: Uuid,
+    scanner: &Scanner,
+    device_id: &str,
+    window: &ScanWindow,
+    scans: &[OfflineScan],
+) -> Result<(Vec<ScanResult>, Vec<ScanConflict>), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    // Oldest first, so a batch resolves the same way in whatever order it was sent
+    let mut order: Vec<usize> = (0..scans.len()).collect();
+    order.sort_by_key(|&index| (scans[index].scanned_at, index));
+
+    let mut tx = db.begin().await?;
+    let mut results = Vec::with_capacity(scans.len());
+    for index in order {
+        let scan = &scans[index];
+        // Stored to the microsecond, so compare at that precision
+        let scanned_at = scan.scanned_at.naive_utc().trunc_subsecs(6);
+        let credential =
+            match validate_credential(&mut *tx, keyring, event_id, &scan.credential).await {
+                Ok(credential) => credential,
+                Err(ScanError::Database(err)) => return Err(err),
+                Err(err) => {
+                    results.push(ScanResult {
+                        index,
+                        booking_id: None,
+                        admission_id: None,
+                        scanned_at,
+                        status: ScanStatus::Rejected,
+                        code: Some(err.code()),
+                        winner: None,
+                    });
+                    continue;
+                }
+            };
+        if let Err(code) = window.check(scanned_at, now) {
+            results.push(ScanResult {
+                index,
+                booking_id: Some(credential.booking_id),
+                admission_id: Some(credential.admission_id),
+                scanned_at,
+                status: ScanStatus::Rejected,
+                code: Some(code),
+                winner: None,
+            });
+            continue;
+        }
+
+        let new_scan = sqlx::query!(
+            "INSERT INTO offline_scans
+                (scan_id, event_id, booking_id, admission_id, device_id, scanned_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (device_id, admission_id, scanned_at) DO NOTHING",
+            Uuid::new_v4(),
+            event_id,
+            credential.booking_id,
+            credential.admission_id,
+            device_id,
+            scanned_at
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+
+        let admission = sqlx::query!(
+            "SELECT verified, checked_in_at, checked_in_device
+            FROM admissions WHERE admission_id = $1
+            FOR UPDATE",
+            credential.admission_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let (status, winner) = if scanned_first(
+            scanned_at,
+            device_id,
+            admission.verified,
+            admission.checked_in_at,
+            admission.checked_in_device.as_deref(),
+        ) {
+            // Taking over from a later scan doesn't count as another entry
+            sqlx::query!(
+                "UPDATE admissions SET
+                    checked_in_at = $1,
+                    checked_in_device = $2,
+                    inside = inside OR NOT verified,
+                    entries = CASE WHEN verified THEN entries ELSE entries + 1 END,
+                    verified = TRUE
+                WHERE admission_id = $3",
+                scanned_at,
+                device_id,
+                credential.admission_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            refresh_booking_verified(&mut *tx, credential.booking_id).await?;
+            (ScanStatus::Accepted, None)
+        } else if admission.checked_in_at == Some(scanned_at)
+            && admission.checked_in_device.as_deref() == Some(device_id)
+        {
+            // The same scan uploaded again
+            (ScanStatus::Accepted, None)
+        } else {
+            let winner = ScanWinner {
+                device_id: admission.checked_in_device,
+                scanned_at: admission.checked_in_at,
+            };
+            (ScanStatus::Conflict, Some(winner))
+        };
+        if new_scan {
+            record_check_in(
+                &mut *tx,
+                ScanRecord {
+                    event_id,
+                    booking_id: credential.booking_id,
+                    admission_id: Some(credential.admission_id),
+                    direction: Direction::In,
+                    denied: (status == ScanStatus::Conflict).then_some(DenyReason::DuplicateScan),
+                    scanner,
+                    device_id: Some(device_id),
+                    scanned_at,
+                },
+            )
+            .await?;
+        }
+        results.push(ScanResult {
+            index,
+            booking_id: Some(credential.booking_id),
+            admission_id: Some(credential.admission_id),
+            scanned_at,
+            status,
+            code: None,
+            winner,
+        });
+    }
+
+    let conflicts = sqlx::query!(
+        "SELECT s.booking_id, s.admission_id, s.scanned_at, a.checked_in_at, a.checked_in_device
+        FROM offline_scans s
+        JOIN admissions a ON a.admission_id = s.admission_id
+        WHERE s.event_id = $1 AND s.device_id = $2
+            AND NOT (a.checked_in_at IS NOT DISTINCT FROM s.scanned_at
+                AND a.checked_in_device IS NOT DISTINCT FROM s.device_id)
+        ORDER BY s.scanned_at",
+        event_id,
+        device_id
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| ScanConflict {
+        booking_id: row.booking_id,
+        admission_id: row.admission_id,
+        scanned_at: row.scanned_at,
+        winner: ScanWinner {
+            device_id: row.checked_in_device,
+            scanned_at: row.checked_in_at,
+        },
+    })
+    .collect();
+
+    tx.commit().await?;
+    results.sort_by_key(|result| result.index);
+    Ok((results, conflicts))
+}
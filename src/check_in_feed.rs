@@ -0,0 +1,478 @@
+use actix_web::web::Bytes;
+use chrono::NaiveDateTime;
+use futures::future::{ready, select, Either};
+use futures::{pin_mut, stream, Stream, StreamExt};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::postgres::{PgListener, Postgres};
+use sqlx::Pool;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, sleep, Interval, MissedTickBehavior};
+use uuid::Uuid;
+
+use crate::models::User;
+use crate::roles::{require_event_role, ORGANIZERS};
+
+// Scans held for each dashboard. One that falls further behind than this is
+// sent a fresh snapshot instead of the scans it missed.
+const FEED_CAPACITY: usize = 256;
+// Recent scans sent to a dashboard when it connects
+const RECENT_SCANS: i64 = 50;
+// Most missed scans replayed to a dashboard that reconnects
+const MAX_REPLAY: i64 = 500;
+// Scans get their seq when inserted but show up when committed, so one can
+// appear after a higher seq was sent. A dashboard that reconnects is sent the
+// scans recorded this long before its last one again, in case it missed some.
+const RESUME_OVERLAP_SECS: f64 = 60.0;
+const TICK: Duration = Duration::from_secs(1);
+// Ticks between keep-alives on a quiet feed, and between access re-checks
+const KEEP_ALIVE_TICKS: u32 = 15;
+const REAUTHORIZE_TICKS: u32 = 60;
+
+// A scan as shown on the live dashboard
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedScan {
+    pub seq: i64,
+    pub check_in_id: Uuid,
+    pub event_id: Uuid,
+    pub booking_id: Uuid,
+    pub admission_id: Option<Uuid>,
+    pub holder_name: Option<String>,
+    pub ticket_id: Option<Uuid>,
+    pub ticket_type: Option<String>,
+    pub direction: String,
+    pub accepted: bool,
+    pub reason: Option<String>,
+    pub gate: Option<String>,
+    pub device_id: Option<String>,
+    pub scanned_at: NaiveDateTime,
+}
+
+async fn scan_by_seq(db: &Pool<Postgres>, seq: i64) -> Result<Option<FeedScan>, sqlx::Error> {
+    sqlx::query_as!(
+        FeedScan,
+        "SELECT c.seq, c.check_in_id, c.event_id, c.booking_id, c.admission_id, a.holder_name,
+            t.ticket_id AS \"ticket_id?\", t.ticket_type, c.direction, c.accepted, c.reason,
+            c.gate, c.device_id, c.scanned_at
+        FROM check_ins c
+        JOIN bookings b ON b.booking_id = c.booking_id
+        LEFT JOIN tickets t ON t.ticket_id = b.ticket_id
+        LEFT JOIN admissions a ON a.admission_id = c.admission_id
+        WHERE c.seq = $1",
+        seq
+    )
+    .fetch_optional(db)
+    .await
+}
+
+// The event's scans after `after`, and those recorded shortly before it that
+// may have committed after it, oldest first. Only the last `limit` are returned.
+async fn scans_after(
+    db: &Pool<Postgres>,
+    event_id: Uuid,
+    after: Option<i64>,
+    limit: i64,
+) -> Result<Vec<FeedScan>, sqlx::Error> {
+    let mut scans = sqlx::query_as!(
+        FeedScan,
+        "SELECT c.seq, c.check_in_id, c.event_id, c.booking_id, c.admission_id, a.holder_name,
+            t.ticket_id AS \"ticket_id?\", t.ticket_type, c.direction, c.accepted, c.reason,
+            c.gate, c.device_id, c.scanned_at
+        FROM check_ins c
+        JOIN bookings b ON b.booking_id = c.booking_id
+        LEFT JOIN tickets t ON t.ticket_id = b.ticket_id
+        LEFT JOIN admissions a ON a.admission_id = c.admission_id
+        WHERE c.event_id = $1
+            AND (
+                c.seq > $2
+                OR c.recorded_at >= (SELECT recorded_at FROM check_ins WHERE seq = $2)
+                    - make_interval(secs => $4)
+            )
+        ORDER BY c.seq DESC
+        LIMIT $3",
+        event_id,
+        after.unwrap_or(0),
+        limit,
+        RESUME_OVERLAP_SECS
+    )
+    .fetch_all(db)
+    .await?;
+    scans.reverse();
+    Ok(scans)
+}
+
+// Admissions on one ticket type and how many of them have come in
+#[derive(Debug, Serialize)]
+pub struct TicketCounts {
+    pub ticket_id: Uuid,
+    pub ticket_type: Option<String>,
+    pub admissions: i64,
+    pub checked_in: i64,
+    pub inside: i64,
+}
+
+// Scans of one ticket type at one gate
+#[derive(Debug, Serialize)]
+pub struct GateCounts {
+    // None for scans that didn't name a gate
+    pub gate: Option<String>,
+    pub ticket_id: Option<Uuid>,
+    pub ticket_type: Option<String>,
+    pub entries: i64,
+    pub exits: i64,
+    pub denied: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedCounts {
+    pub tickets: Vec<TicketCounts>,
+    pub gates: Vec<GateCounts>,
+}
+
+/// Check-in counts for an event by ticket type, and by gate and ticket type.
+pub async fn feed_counts(db: &Pool<Postgres>, event_id: Uuid) -> Result<FeedCounts, sqlx::Error> {
+    let tickets = sqlx::query_as!(
+        TicketCounts,
+        "SELECT t.ticket_id, t.ticket_type,
+            COUNT(a.admission_id) AS \"admissions!\",
+            COUNT(*) FILTER (WHERE a.verified) AS \"checked_in!\",
+            COUNT(*) FILTER (WHERE a.inside) AS \"inside!\"
+        FROM tickets t
+        LEFT JOIN bookings b ON b.ticket_id = t.ticket_id AND b.user_id IS NOT NULL
+        LEFT JOIN admissions a ON a.booking_id = b.booking_id
+        WHERE t.event_id = $1
+        GROUP BY t.ticket_id, t.ticket_type
+        ORDER BY t.ticket_type, t.ticket_id",
+        event_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let gates = sqlx::query_as!(
+        GateCounts,
+        "SELECT c.gate, t.ticket_id AS \"ticket_id?\", t.ticket_type,
+            COUNT(*) FILTER (WHERE c.accepted AND c.direction = 'in') AS \"entries!\",
+            COUNT(*) FILTER (WHERE c.accepted AND c.direction = 'out') AS \"exits!\",
+            COUNT(*) FILTER (WHERE NOT c.accepted) AS \"denied!\"
+        FROM check_ins c
+        JOIN bookings b ON b.booking_id = c.booking_id
+        LEFT JOIN tickets t ON t.ticket_id = b.ticket_id
+        WHERE c.event_id = $1
+        GROUP BY c.gate, t.ticket_id, t.ticket_type
+        ORDER BY c.gate NULLS FIRST, t.ticket_type, t.ticket_id",
+        event_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(FeedCounts { tickets, gates })
+}
+
+/// Fans scans out to the dashboards connected to this server.
+pub struct CheckInFeed {
+    sender: broadcast::Sender<Arc<FeedScan>>,
+}
+
+impl Default for CheckInFeed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        CheckInFeed { sender }
+    }
+}
+
+impl CheckInFeed {
+    /// Passes on every scan committed to check_ins, by this server or any
+    /// other, as Postgres announces them. Runs for the life of the process.
+    pub async fn listen(&self, db: Pool<Postgres>) {
+        let mut listener = loop {
+            match PgListener::connect_with(&db).await {
+                Ok(mut listener) => match listener.listen("check_ins").await {
+                    Ok(()) => break listener,
+                    Err(err) => log::error!("Failed to listen for check-ins: {}", err),
+                },
+                Err(err) => log::error!("Failed to connect check-in listener: {}", err),
+            }
+            sleep(Duration::from_secs(5)).await;
+        };
+
+        loop {
+            // The listener reconnects by itself; scans made while it was away
+            // still show up in the dashboards' counts
+            let notification = match listener.recv().await {
+                Ok(notification) => notification,
+                Err(err) => {
+                    log::warn!("Check-in listener lost its connection: {}", err);
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let Ok(seq) = notification.payload().parse::<i64>() else {
+                continue;
+            };
+            match scan_by_seq(&db, seq).await {
+                // Sending only fails when no dashboard is connected
+                Ok(Some(scan)) => {
+                    let _ = self.sender.send(Arc::new(scan));
+                }
+                Ok(None) => {}
+                Err(err) => log::warn!("Failed to load check-in {}: {}", seq, err),
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    counts: FeedCounts,
+    scans: Vec<FeedScan>,
+    // True when `scans` is every scan since the dashboard's last event, so it
+    // can add them to its list rather than start over. They may include scans
+    // it already has, which it can tell by check_in_id.
+    resumed: bool,
+}
+
+// One dashboard's connection to the feed
+struct FeedConnection {
+    db: Pool<Postgres>,
+    user: User,
+    session_id: Uuid,
+    event_id: Uuid,
+    scans: broadcast::Receiver<Arc<FeedScan>>,
+    ticker: Interval,
+    // A snapshot to send next, with the last scan the dashboard has seen
+    snapshot: Option<Option<i64>>,
+    // Scans already sent in the last snapshot that may also arrive live
+    replayed: HashSet<i64>,
+    counts_stale: bool,
+    ticks: u32,
+    quiet_ticks: u32,
+    closed: bool,
+}
+
+fn message(event: &str, id: Option<i64>, data: &impl Serialize) -> Bytes {
+    let mut message = format!("event: {}\n", event);
+    if let Some(id) = id {
+        message.push_str(&format!("id: {}\n", id));
+    }
+    let data = serde_json::to_string(data).unwrap_or_else(|_| "null".to_string());
+    message.push_str(&format!("data: {}\n\n", data));
+    Bytes::from(message)
+}
+
+impl FeedConnection {
+    async fn next_message(&mut self) -> Option<Bytes> {
+        if self.closed {
+            return None;
+        }
+        if let Some(after) = self.snapshot.take() {
+            return Some(self.send_snapshot(after).await);
+        }
+
+        loop {
+            let received = {
+                let scan = self.scans.recv();
+                let tick = self.ticker.tick();
+                pin_mut!(scan, tick);
+                match select(scan, tick).await {
+                    Either::Left((scan, _)) => Some(scan),
+                    Either::Right(_) => None,
+                }
+            };
+            match received {
+                Some(Ok(scan)) => {
+                    if scan.event_id != self.event_id || self.replayed.remove(&scan.seq) {
+                        continue;
+                    }
+                    self.counts_stale = true;
+                    self.quiet_ticks = 0;
+                    return Some(message("scan", Some(scan.seq), &*scan));
+                }
+                // Too slow to keep up, so skip to where things stand now
+                Some(Err(RecvError::Lagged(_))) => return Some(self.send_snapshot(None).await),
+                Some(Err(RecvError::Closed)) => return None,
+                None => {
+                    self.ticks += 1;
+                    if self.ticks.is_multiple_of(REAUTHORIZE_TICKS) && !self.authorized().await {
+                        self.closed = true;
+                        return Some(message(
+                            "error",
+                            None,
+                            &json!({ "error": "You no longer have access to this event" }),
+                        ));
+                    }
+                    // Counts go out at most once a tick, however busy the doors are
+                    if self.counts_stale {
+                        self.counts_stale = false;
+                        self.quiet_ticks = 0;
+                        return Some(match feed_counts(&self.db, self.event_id).await {
+                            Ok(counts) => message("counts", None, &counts),
+                            Err(err) => self.fail(err),
+                        });
+                    }
+                    self.quiet_ticks += 1;
+                    if self.quiet_ticks >= KEEP_ALIVE_TICKS {
+                        self.quiet_ticks = 0;
+                        return Some(Bytes::from_static(b": keep-alive\n\n"));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_snapshot(&mut self, after: Option<i64>) -> Bytes {
+        let limit = if after.is_some() {
+            MAX_REPLAY
+        } else {
+            RECENT_SCANS
+        };
+        let counts = match feed_counts(&self.db, self.event_id).await {
+            Ok(counts) => counts,
+            Err(err) => return self.fail(err),
+        };
+        let scans = match scans_after(&self.db, self.event_id, after, limit).await {
+            Ok(scans) => scans,
+            Err(err) => return self.fail(err),
+        };
+
+        self.replayed = scans.iter().map(|scan| scan.seq).collect();
+        self.counts_stale = false;
+        let last_seq = scans.last().map(|scan| scan.seq).or(after);
+        let snapshot = Snapshot {
+            counts,
+            resumed: after.is_some() && (scans.len() as i64) < limit,
+            scans,
+        };
+        message("snapshot", last_seq, &snapshot)
+    }
+
+    // The session must still be live and the user still one of the event's organizers
+    async fn authorized(&self) -> bool {
+        let session = sqlx::query_scalar!(
+            "SELECT session_id FROM sessions WHERE session_id = $1 AND revoked_at IS NULL",
+            self.session_id
+        )
+        .fetch_optional(&self.db)
+        .await;
+        matches!(session, Ok(Some(_)))
+            && require_event_role(&self.db, &self.user, self.event_id, ORGANIZERS)
+                .await
+                .is_ok()
+    }
+
+    // Ends the stream; the dashboard reconnects and resumes from its last event
+    fn fail(&mut self, err: sqlx::Error) -> Bytes {
+        log::error!("Check-in feed for event {} failed: {}", self.event_id, err);
+        self.closed = true;
+        message("error", None, &json!({ "error": err.to_string() }))
+    }
+}
+
+/// Server-sent events for an event's live dashboard. Starts with a snapshot of
+/// the counts and recent scans, or the scans since `last_event_id` when
+/// resuming, then streams each scan followed by the updated counts. Scans
+/// arrive in the order they commit, so their ids aren't always increasing.
+pub fn feed_stream(
+    db: Pool<Postgres>,
+    feed: &CheckInFeed,
+    user: User,
+    session_id: Uuid,
+    event_id: Uuid,
+    last_event_id: Option<i64>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let mut ticker = interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let connection = FeedConnection {
+        db,
+        user,
+        session_id,
+        event_id,
+        // Subscribed before the snapshot is read so no scan falls in between
+        scans: feed.sender.subscribe(),
+        ticker,
+        snapshot: Some(last_event_id),
+        replayed: HashSet::new(),
+        counts_stale: false,
+        ticks: 0,
+        quiet_ticks: 0,
+        closed: false,
+    };
+
+    stream::once(ready(Bytes::from_static(b"retry: 3000\n\n")))
+        .chain(stream::unfold(connection, |mut connection| async move {
+            let message = connection.next_message().await?;
+            Some((message, connection))
+        }))
+        .map(Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::Role;
+    use crate::test_support::{create_event, create_ticket, create_user, delete_users, test_state};
+
+    async fn insert_scan<'e, E>(executor: E, event_id: Uuid, booking_id: Uuid) -> i64
+    where
+        E: sqlx::Executor<'e, Database = Postgres>,
+    {
+        sqlx::query_scalar!(
+            "INSERT INTO check_ins
+                (check_in_id, event_id, booking_id, direction, accepted, scanned_at)
+            VALUES ($1, $2, $3, 'in', TRUE, NOW())
+            RETURNING seq",
+            Uuid::new_v4(),
+            event_id,
+            booking_id
+        )
+        .fetch_one(executor)
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn resuming_includes_scans_that_committed_late() {
+        let state = test_state().await;
+        let organizer = create_user(&state, Role::Organizer).await;
+        let event_id = create_event(&state, &organizer).await;
+        let ticket_id = create_ticket(&state, event_id, 10).await;
+        let booking_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO bookings (booking_id, user_id, ticket_id, quantity, total_price)
+            VALUES ($1, $2, $3, 1, 100)",
+            booking_id,
+            organizer.user_id,
+            ticket_id
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let earlier = insert_scan(&state.db, event_id, booking_id).await;
+        // Numbered first, committed last
+        let mut tx = state.db.begin().await.unwrap();
+        let late = insert_scan(&mut *tx, event_id, booking_id).await;
+        let seen = insert_scan(&state.db, event_id, booking_id).await;
+        assert!(late < seen);
+        let before_commit = scans_after(&state.db, event_id, Some(seen), MAX_REPLAY)
+            .await
+            .unwrap();
+        assert!(before_commit.iter().all(|scan| scan.seq != late));
+        tx.commit().await.unwrap();
+
+        let resumed: Vec<i64> = scans_after(&state.db, event_id, Some(seen), MAX_REPLAY)
+            .await
+            .unwrap()
+            .iter()
+            .map(|scan| scan.seq)
+            .collect();
+        assert!(resumed.contains(&late));
+        // Overlapping scans come too, oldest first
+        assert_eq!(resumed, vec![earlier, late, seen]);
+
+        delete_users(&state, &[&organizer]).await;
+    }
+}
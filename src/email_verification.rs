@@ -0,0 +1,158 @@
+use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::Postgres, Pool};
+use std::env;
+use uuid::Uuid;
+
+use crate::keyring::Keyring;
+use crate::mailer::{Email, Mailer};
+use crate::models::User;
+
+const TOKEN_HOURS: i64 = 24;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VerificationClaims {
+    sub: String,
+    // The address being verified; a token stops working if the email changes
+    email: String,
+    exp: i64,
+    jti: String,
+}
+
+// Actions that can be held back until the user has verified their email
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatedAction {
+    BookTicket,
+    CreateEvent,
+}
+
+impl GatedAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GatedAction::BookTicket => "book_ticket",
+            GatedAction::CreateEvent => "create_event",
+        }
+    }
+}
+
+/// Whether `action` requires a verified email. Configured with the
+/// comma-separated REQUIRE_VERIFIED_EMAIL_FOR variable, which defaults to
+/// `book_ticket,create_event`; set it to an empty string to disable.
+pub fn verification_required(action: GatedAction) -> bool {
+    let policy = env::var("REQUIRE_VERIFIED_EMAIL_FOR")
+        .unwrap_or_else(|_| "book_ticket,create_event".to_string());
+    policy.split(',').any(|item| item.trim() == action.as_str())
+}
+
+pub fn require_verified_email(user: &User, action: GatedAction) -> Result<(), HttpResponse> {
+    if user.email_verified_at.is_some() || !verification_required(action) {
+        return Ok(());
+    }
+    Err(HttpResponse::Forbidden().json(json!({
+        "status": "fail",
+        "code": "email_not_verified",
+        "message": "Please verify your email address first"
+    })))
+}
+
+/// Issues a single-use verification token for the user's current email and
+/// mails them the link.
+pub async fn send_verification_email(
+    db: &Pool<Postgres>,
+    mailer: &dyn Mailer,
+    keyring: &Keyring,
+    user: &User,
+) -> Result<(), String> {
+    let token_id = Uuid::new_v4();
+    let expires_in = (Utc::now() + chrono::Duration::hours(TOKEN_HOURS)).timestamp();
+    let claims = VerificationClaims {
+        sub: user.user_id.to_string(),
+        email: user.email.clone(),
+        exp: expires_in,
+        jti: token_id.to_string(),
+    };
+    let token = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        keyring.email_signing_key(),
+    )
+    .map_err(|err| err.to_string())?;
+
+    let expires_at = DateTime::<Utc>::from_timestamp(expires_in, 0)
+        .unwrap_or_default()
+        .naive_utc();
+    sqlx::query!(
+        "INSERT INTO email_verifications (token_id, user_id, expires_at) VALUES ($1, $2, $3)",
+        token_id,
+        user.user_id,
+        expires_at
+    )
+    .execute(db)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    let app_url = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Verify your KriyaPass email".to_string(),
+            body: format!(
+                "Confirm your email address by opening this link within {} hours:\n{}/verify-email?token={}",
+                TOKEN_HOURS, app_url, token
+            ),
+        })
+        .await
+}
+
+/// Redeems a verification token, marking the user's email as verified.
+/// Returns false if the token is invalid, expired, already used, or was
+/// issued for an address the user no longer has.
+pub async fn verify_email_token(
+    db: &Pool<Postgres>,
+    keyring: &Keyring,
+    token: &str,
+) -> Result<bool, sqlx::Error> {
+    let claims = match decode::<VerificationClaims>(
+        token,
+        keyring.email_verification_key(),
+        &Validation::new(Algorithm::HS256),
+    ) {
+        Ok(decoded) => decoded.claims,
+        Err(_) => return Ok(false),
+    };
+    let (Ok(user_id), Ok(token_id)) = (Uuid::parse_str(&claims.sub), Uuid::parse_str(&claims.jti))
+    else {
+        return Ok(false);
+    };
+
+    let mut tx = db.begin().await?;
+    let redeemed = sqlx::query!(
+        "UPDATE email_verifications SET used_at = NOW()
+        WHERE token_id = $1 AND user_id = $2 AND used_at IS NULL AND expires_at > NOW()",
+        token_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if redeemed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let verified = sqlx::query!(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+        WHERE user_id = $1 AND email = $2",
+        user_id,
+        claims.email
+    )
+    .execute(&mut *tx)
+    .await?;
+    if verified.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    tx.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::Postgres, Pool, Transaction};
+use uuid::Uuid;
+
+use crate::event_status::EventStatus;
+use crate::mailer::{Email, Mailer};
+use crate::models::Event;
+
+// A field of an event with its value before and after an edit
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+impl FieldChange {
+    /// A status change, for the audit trail.
+    pub fn status(old: EventStatus, new: EventStatus) -> Self {
+        FieldChange {
+            field: "event_status",
+            old_value: Some(old.as_str().to_string()),
+            new_value: Some(new.as_str().to_string()),
+        }
+    }
+
+    // Moving the date or venue may mean attendees can no longer make it. Of
+    // status changes they hear about the event being called off or back on,
+    // not about sales pausing.
+    pub fn concerns_attendees(&self) -> bool {
+        match self.field {
+            "starts_at" | "ends_at" | "doors_open_at" | "event_location" => true,
+            "event_status" => [&self.old_value, &self.new_value]
+                .into_iter()
+                .any(|value| matches!(value.as_deref(), Some("cancelled" | "postponed"))),
+            _ => false,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self.field {
+            "event_name" => "Name",
+            "event_date" => "Date",
+            "starts_at" => "Starts",
+            "ends_at" => "Ends",
+            "doors_open_at" => "Doors open",
+            "timezone" => "Timezone",
+            "event_location" => "Venue",
+            "event_status" => "Status",
+            "category" => "Category",
+            _ => "Description",
+        }
+    }
+
+    // A value as attendees should read it
+    fn display(&self, value: &Option<String>) -> String {
+        match value.as_deref() {
+            None => "not set".to_string(),
+            Some(value) if self.field == "event_status" => EventStatus::parse(value)
+                .map_or(value, |status| status.label())
+                .to_string(),
+            Some(value) if self.field.ends_with("_at") => DateTime::parse_from_rfc3339(value)
+                .map_or(value.to_string(), |at| {
+                    at.format("%a %-d %b %Y, %H:%M (UTC%:z)").to_string()
+                }),
+            Some(value) => value.to_string(),
+        }
+    }
+}
+
+// Times are kept in the audit trail as the event's local time, with its offset
+fn local_time(event: &Event, at: DateTime<Utc>) -> String {
+    at.with_timezone(&event.schedule().timezone).to_rfc3339()
+}
+
+/// The fields that differ between an event before and after an edit.
+pub fn diff_events(before: &Event, after: &Event) -> Vec<FieldChange> {
+    [
+        (
+            "event_name",
+            Some(before.event_name.clone()),
+            Some(after.event_name.clone()),
+        ),
+        (
+            "event_date",
+            Some(before.event_date.to_string()),
+            Some(after.event_date.to_string()),
+        ),
+        (
+            "starts_at",
+            Some(local_time(before, before.starts_at)),
+            Some(local_time(after, after.starts_at)),
+        ),
+        (
+            "ends_at",
+            Some(local_time(before, before.ends_at)),
+            Some(local_time(after, after.ends_at)),
+        ),
+        (
+            "doors_open_at",
+            before.doors_open_at.map(|at| local_time(before, at)),
+            after.doors_open_at.map(|at| local_time(after, at)),
+        ),
+        (
+            "timezone",
+            Some(before.timezone.clone()),
+            Some(after.timezone.clone()),
+        ),
+        (
+            "event_location",
+            before.event_location.clone(),
+            after.event_location.clone(),
+        ),
+        (
+            "event_description",
+            before.event_description.clone(),
+            after.event_description.clone(),
+        ),
+        ("category", before.category.clone(), after.category.clone()),
+        (
+            "event_status",
+            Some(before.event_status.clone()),
+            Some(after.event_status.clone()),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, old_value, new_value)| old_value != new_value)
+    .map(|(field, old_value, new_value)| FieldChange {
+        field,
+        old_value,
+        new_value,
+    })
+    .collect()
+}
+
+/// Writes one edit of an event to its audit trail. `changed_by` is None for
+/// changes the server makes by itself.
+pub async fn record_event_changes(
+    tx: &mut Transaction<'_, Postgres>,
+    event_id: Uuid,
+    changed_by: Option<Uuid>,
+    changes: &[FieldChange],
+) -> Result<(), sqlx::Error> {
+    let update_id = Uuid::new_v4();
+    for change in changes {
+        sqlx::query!(
+            "INSERT INTO event_changes
+                (change_id, update_id, event_id, changed_by, field, old_value, new_value)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            Uuid::new_v4(),
+            update_id,
+            event_id,
+            changed_by,
+            change.field,
+            change.old_value,
+            change.new_value
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Emails everyone holding a booking for the event about a change to it.
+/// Returns how many were sent.
+pub async fn notify_attendees(
+    db: &Pool<Postgres>,
+    mailer: &dyn Mailer,
+    event: &Event,
+    changes: &[FieldChange],
+) -> Result<usize, String> {
+    let emails = sqlx::query_scalar!(
+        "SELECT DISTINCT u.email
+        FROM bookings b
+        JOIN tickets t ON t.ticket_id = b.ticket_id
+        JOIN users u ON u.user_id = b.user_id
+        WHERE t.event_id = $1",
+        event.event_id
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    let details: Vec<String> = changes
+        .iter()
+        // The start time covers the date, and times carry their own offset
+        .filter(|change| {
+            !matches!(
+                change.field,
+                "event_description" | "event_date" | "timezone" | "category"
+            )
+        })
+        .map(|change| {
+            format!(
+                "{}: {} (was {})",
+                change.label(),
+                change.display(&change.new_value),
+                change.display(&change.old_value)
+            )
+        })
+        .collect();
+    let closing = if event.status() == EventStatus::Cancelled {
+        "Please contact the organizer about your booking."
+    } else {
+        "Your booking still stands. If you can no longer attend, you can cancel it from your bookings."
+    };
+    let body = format!(
+        "The organizer of {}, which you have a booking for, has changed its details:\n\n{}\n\n{}",
+        event.event_name,
+        details.join("\n"),
+        closing
+    );
+
+    let mut sent = 0;
+    for to in emails {
+        // One bad address shouldn't keep the others from hearing about it
+        match mailer
+            .send(Email {
+                to: to.clone(),
+                subject: format!("{} has changed", event.event_name),
+                body: body.clone(),
+            })
+            .await
+        {
+            Ok(()) => sent += 1,
+            Err(err) => log::warn!("Failed to tell {} about event change: {}", to, err),
+        }
+    }
+    Ok(sent)
+}
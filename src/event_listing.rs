@@ -0,0 +1,275 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::Postgres, Pool};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::event_status::EventStatus;
+use crate::models::{Event, EventQuery};
+use crate::pagination::{Cursor, Page, PageError};
+
+// Orders of the event catalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSort {
+    // Soonest to start first
+    #[default]
+    Date,
+    // Most admissions booked first
+    Popularity,
+    // Most recently created first
+    Newest,
+    // Best match for the search first
+    Relevance,
+}
+
+impl EventSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventSort::Date => "date",
+            EventSort::Popularity => "popularity",
+            EventSort::Newest => "newest",
+            EventSort::Relevance => "relevance",
+        }
+    }
+}
+
+// How similar to a name or venue a search must be to match it in spite of
+// typos, from 0 to 1
+const TYPO_THRESHOLD: &str = "0.5";
+
+impl EventQuery {
+    // The search, if there's anything to search for
+    pub fn search(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+
+    pub fn sort(&self) -> EventSort {
+        match (self.sort, self.search()) {
+            (Some(sort), _) => sort,
+            (None, Some(_)) => EventSort::Relevance,
+            (None, None) => EventSort::Date,
+        }
+    }
+}
+
+/// Where an event falls in a sort. Each sort is turned into one number that
+/// ascends, so the same keyset query serves them all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SortKey {
+    pub sort: EventSort,
+    pub value: Decimal,
+}
+
+/// The page of the catalog asked for. A cursor only carries on the sort it
+/// came from.
+pub fn event_page(query: &EventQuery) -> Result<Page<SortKey>, String> {
+    let page: Page<SortKey> =
+        Page::new(query.cursor.as_deref(), query.limit).map_err(|err| err.to_string())?;
+    let sort = query.sort();
+    if sort == EventSort::Relevance && query.search().is_none() {
+        return Err("Sorting by relevance needs a search in q".to_string());
+    }
+    if page.after_key().is_some_and(|key| key.sort != sort) {
+        return Err(PageError::BadCursor.to_string());
+    }
+    Ok(page)
+}
+
+/// A page of the events `user_id` can see, filtered and sorted as asked,
+/// with the cursor for the next page. Published events are seen by all,
+/// others by the event's members, and everything by admins.
+///
+/// A search matches events whose name, venue or description contain its
+/// words, and failing that, names and venues close to it. The latter rank
+/// below every event matching the words themselves.
+///
+/// Popularity is read as each page is fetched, so an event whose bookings
+/// change while someone is paging may move past or behind their cursor.
+pub async fn list_events(
+    db: &Pool<Postgres>,
+    query: &EventQuery,
+    page: &Page<SortKey>,
+    user_id: Option<Uuid>,
+    admin: bool,
+) -> Result<(Vec<Event>, Option<String>), sqlx::Error> {
+    let sort = query.sort();
+    let search = query.search();
+    let mut tx = db.begin().await?;
+    if search.is_some() {
+        sqlx::query!(
+            "SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)",
+            TYPO_THRESHOLD
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+    }
+    let listed: Vec<&str> = EventStatus::ALL
+        .into_iter()
+        .filter(EventStatus::is_listed)
+        .map(|status| status.as_str())
+        .collect();
+    let rows = sqlx::query!(
+        "SELECT e.event_id, k.value AS \"value!\"
+        FROM events e
+        CROSS JOIN LATERAL (
+            SELECT
+                event_search_document(e.event_name, e.event_location, e.event_description)
+                    AS document,
+                websearch_to_tsquery('english', $17) AS query
+        ) s
+        CROSS JOIN LATERAL (
+            SELECT CASE $1
+                WHEN 'popularity' THEN -(
+                    SELECT COALESCE(SUM(b.quantity), 0)
+                    FROM bookings b
+                    JOIN tickets t ON t.ticket_id = b.ticket_id
+                    WHERE t.event_id = e.event_id
+                )
+                WHEN 'newest' THEN -EXTRACT(EPOCH FROM e.created_at)
+                WHEN 'relevance' THEN -(
+                    CASE WHEN s.document @@ s.query THEN 1 + ts_rank(s.document, s.query)
+                    ELSE GREATEST(
+                        word_similarity($17, e.event_name),
+                        word_similarity($17, COALESCE(e.event_location, ''))
+                    )
+                    END
+                )::NUMERIC
+                ELSE EXTRACT(EPOCH FROM e.starts_at)
+            END AS value
+        ) k
+        WHERE (
+                e.event_status = ANY($2) OR $4
+                OR EXISTS (
+                    SELECT 1 FROM event_members m WHERE m.event_id = e.event_id AND m.user_id = $3
+                )
+            )
+            AND ($5::DATE IS NULL OR e.event_date >= $5)
+            AND ($6::DATE IS NULL OR e.event_date <= $6)
+            AND ($7::TEXT IS NULL OR POSITION(LOWER($7) IN LOWER(e.event_location)) > 0)
+            AND ($8::TEXT IS NULL OR e.event_status = $8)
+            AND ($9::UUID IS NULL OR e.user_id = $9)
+            AND ($10::TEXT IS NULL OR LOWER(e.category) = LOWER($10))
+            AND (
+                ($11::NUMERIC IS NULL AND $12::NUMERIC IS NULL AND NOT $13)
+                OR EXISTS (
+                    SELECT 1 FROM tickets t
+                    WHERE t.event_id = e.event_id
+                        AND ($11 IS NULL OR t.price >= $11)
+                        AND ($12 IS NULL OR t.price <= $12)
+                        AND (NOT $13 OR t.availability > 0)
+                )
+            )
+            AND (
+                $17::TEXT IS NULL
+                OR event_search_document(e.event_name, e.event_location, e.event_description)
+                    @@ websearch_to_tsquery('english', $17)
+                OR $17 <% e.event_name
+                OR $17 <% e.event_location
+            )
+            AND ($14::NUMERIC IS NULL OR (k.value, e.event_id) > ($14, $15))
+        ORDER BY k.value, e.event_id
+        LIMIT $16",
+        sort.as_str(),
+        &listed[..] as &[&str],
+        user_id,
+        admin,
+        query.from,
+        query.to,
+        query.location.as_deref().map(str::trim),
+        query.status.map(|status| status.as_str()),
+        query.organizer,
+        query.category.as_deref().map(str::trim),
+        query.min_price,
+        query.max_price,
+        query.available.unwrap_or(false),
+        page.after_key().map(|key| key.value),
+        page.after_id(),
+        page.fetch_limit(),
+        search
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let (rows, next_cursor) = page.finish(rows, |row| Cursor {
+        key: SortKey {
+            sort,
+            value: row.value,
+        },
+        id: row.event_id,
+    });
+    let event_ids: Vec<Uuid> = rows.iter().map(|row| row.event_id).collect();
+    let mut events = sqlx::query_as!(
+        Event,
+        "SELECT * FROM events WHERE event_id = ANY($1)",
+        &event_ids[..]
+    )
+    .fetch_all(db)
+    .await?;
+    events.sort_by_key(|event| event_ids.iter().position(|id| *id == event.event_id));
+    Ok((events, next_cursor))
+}
+
+/// Where a search matched an event, as HTML: the text escaped, with the
+/// matched words in <mark>. The description is cut down to the parts around
+/// the matches.
+#[derive(Debug, Serialize)]
+pub struct Highlight {
+    pub event_name: String,
+    pub event_location: Option<String>,
+    pub event_description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub event: Event,
+    pub highlight: Highlight,
+}
+
+/// Highlights `search` in a page of events found by it.
+pub async fn search_hits(
+    db: &Pool<Postgres>,
+    search: &str,
+    events: Vec<Event>,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let event_ids: Vec<Uuid> = events.iter().map(|event| event.event_id).collect();
+    let mut highlights: HashMap<Uuid, Highlight> = sqlx::query!(
+        "SELECT e.event_id,
+            ts_headline('english', html_escape(e.event_name), q.query,
+                'HighlightAll=true, StartSel=<mark>, StopSel=</mark>') AS \"event_name!\",
+            ts_headline('english', html_escape(e.event_location), q.query,
+                'HighlightAll=true, StartSel=<mark>, StopSel=</mark>') AS event_location,
+            ts_headline('english', html_escape(e.event_description), q.query,
+                'StartSel=<mark>, StopSel=</mark>, MinWords=15, MaxWords=35, MaxFragments=2')
+                AS event_description
+        FROM events e
+        CROSS JOIN websearch_to_tsquery('english', $2) AS q(query)
+        WHERE e.event_id = ANY($1)",
+        &event_ids[..],
+        search
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.event_id,
+            Highlight {
+                event_name: row.event_name,
+                event_location: row.event_location,
+                event_description: row.event_description,
+            },
+        )
+    })
+    .collect();
+
+    Ok(events
+        .into_iter()
+        .filter_map(|event| {
+            let highlight = highlights.remove(&event.event_id)?;
+            Some(SearchHit { event, highlight })
+        })
+        .collect())
+}
@@ -0,0 +1,388 @@
+use chrono::{offset::LocalResult, DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use core::fmt;
+
+use crate::models::{Event, EventUpdate, NewEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleError {
+    UnknownTimezone,
+    InvalidDate,
+    // Names the field that couldn't be read
+    InvalidTime(&'static str),
+    MissingStart,
+    MissingEnd,
+    // A local time skipped when the clocks go forward
+    NonexistentTime(&'static str),
+    EndsBeforeStart,
+    DoorsAfterStart,
+    // A time pushed past the latest date that can be stored
+    OutOfRange(&'static str),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::UnknownTimezone => {
+                write!(f, "Timezone must be an IANA name such as Asia/Kolkata")
+            }
+            ScheduleError::InvalidDate => write!(f, "event_date must be in YYYY-MM-DD format"),
+            ScheduleError::InvalidTime(field) => write!(
+                f,
+                "{} must be a local time like 2026-12-01T19:00, or RFC 3339 with an offset",
+                field
+            ),
+            ScheduleError::MissingStart => write!(f, "Give the event a starts_at or event_date"),
+            ScheduleError::MissingEnd => write!(f, "ends_at is required with starts_at"),
+            ScheduleError::NonexistentTime(field) => write!(
+                f,
+                "{} falls in the hour skipped by a clock change in the event's timezone",
+                field
+            ),
+            ScheduleError::EndsBeforeStart => write!(f, "Event must end after it starts"),
+            ScheduleError::DoorsAfterStart => write!(f, "Doors must open before the event starts"),
+            ScheduleError::OutOfRange(field) => write!(f, "{} is too far in the future", field),
+        }
+    }
+}
+
+// Schedule fields as sent by a client. Left out fields are None.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScheduleInput<'a> {
+    pub event_date: Option<&'a str>,
+    pub starts_at: Option<&'a str>,
+    pub ends_at: Option<&'a str>,
+    // An empty string means doors open when the event starts
+    pub doors_open_at: Option<&'a str>,
+    pub timezone: Option<&'a str>,
+}
+
+impl ScheduleInput<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.event_date.is_none()
+            && self.starts_at.is_none()
+            && self.ends_at.is_none()
+            && self.doors_open_at.is_none()
+            && self.timezone.is_none()
+    }
+}
+
+/// When an event runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventSchedule {
+    pub timezone: Tz,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub doors_open_at: Option<DateTime<Utc>>,
+}
+
+impl EventSchedule {
+    // The local date the event starts on, kept in events.event_date
+    pub fn event_date(&self) -> NaiveDate {
+        self.starts_at.with_timezone(&self.timezone).date_naive()
+    }
+
+    fn to_local(self) -> LocalSchedule {
+        let local = |at: DateTime<Utc>| at.with_timezone(&self.timezone).naive_local();
+        LocalSchedule {
+            timezone: self.timezone,
+            starts_at: local(self.starts_at),
+            ends_at: local(self.ends_at),
+            doors_open_at: self.doors_open_at.map(local),
+        }
+    }
+}
+
+// The same, as wall-clock times in the event's timezone
+struct LocalSchedule {
+    timezone: Tz,
+    starts_at: NaiveDateTime,
+    ends_at: NaiveDateTime,
+    doors_open_at: Option<NaiveDateTime>,
+}
+
+impl LocalSchedule {
+    // Moves the event to start at `starts_at`, keeping its length and how
+    // long before the start the doors open
+    fn move_to(&mut self, starts_at: NaiveDateTime) -> Result<(), ScheduleError> {
+        let shift = starts_at - self.starts_at;
+        self.starts_at = starts_at;
+        self.ends_at = self
+            .ends_at
+            .checked_add_signed(shift)
+            .ok_or(ScheduleError::OutOfRange("ends_at"))?;
+        self.doors_open_at = self
+            .doors_open_at
+            .map(|doors| {
+                doors
+                    .checked_add_signed(shift)
+                    .ok_or(ScheduleError::OutOfRange("doors_open_at"))
+            })
+            .transpose()?;
+        Ok(())
+    }
+
+    fn resolve(&self) -> Result<EventSchedule, ScheduleError> {
+        let schedule = EventSchedule {
+            timezone: self.timezone,
+            starts_at: resolve_time(self.timezone, self.starts_at, "starts_at")?,
+            ends_at: resolve_time(self.timezone, self.ends_at, "ends_at")?,
+            doors_open_at: self
+                .doors_open_at
+                .map(|doors| resolve_time(self.timezone, doors, "doors_open_at"))
+                .transpose()?,
+        };
+        if schedule.ends_at <= schedule.starts_at {
+            return Err(ScheduleError::EndsBeforeStart);
+        }
+        if schedule
+            .doors_open_at
+            .is_some_and(|doors| doors > schedule.starts_at)
+        {
+            return Err(ScheduleError::DoorsAfterStart);
+        }
+        Ok(schedule)
+    }
+}
+
+// A local time repeated when the clocks go back is taken as the first of the two
+fn resolve_time(
+    timezone: Tz,
+    local: NaiveDateTime,
+    field: &'static str,
+) -> Result<DateTime<Utc>, ScheduleError> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => Ok(at.with_timezone(&Utc)),
+        LocalResult::None => Err(ScheduleError::NonexistentTime(field)),
+    }
+}
+
+fn parse_timezone(timezone: &str) -> Result<Tz, ScheduleError> {
+    timezone
+        .trim()
+        .parse()
+        .map_err(|_| ScheduleError::UnknownTimezone)
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, ScheduleError> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").map_err(|_| ScheduleError::InvalidDate)
+}
+
+// Reads a wall-clock time in `timezone`, or an RFC 3339 instant converted to one
+fn parse_local_time(
+    value: &str,
+    timezone: Tz,
+    field: &'static str,
+) -> Result<NaiveDateTime, ScheduleError> {
+    let value = value.trim();
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&timezone).naive_local());
+    }
+    [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+    ]
+    .into_iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .ok_or(ScheduleError::InvalidTime(field))
+}
+
+fn parse_doors(value: &str, timezone: Tz) -> Result<Option<NaiveDateTime>, ScheduleError> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    parse_local_time(value, timezone, "doors_open_at").map(Some)
+}
+
+/// Works out a new event's schedule. Times without an offset are local to
+/// the event's timezone, which defaults to UTC. With only an event_date the
+/// event takes up that whole day.
+pub fn new_schedule(input: ScheduleInput<'_>) -> Result<EventSchedule, ScheduleError> {
+    let timezone = match input.timezone {
+        Some(timezone) => parse_timezone(timezone)?,
+        None => Tz::UTC,
+    };
+    let (starts_at, ends_at) = match (input.starts_at, input.event_date) {
+        (Some(starts_at), _) => {
+            let ends_at = input.ends_at.ok_or(ScheduleError::MissingEnd)?;
+            (
+                parse_local_time(starts_at, timezone, "starts_at")?,
+                parse_local_time(ends_at, timezone, "ends_at")?,
+            )
+        }
+        (None, Some(date)) => {
+            let starts_at = parse_date(date)?.and_time(NaiveTime::MIN);
+            let ends_at = match input.ends_at {
+                Some(ends_at) => parse_local_time(ends_at, timezone, "ends_at")?,
+                None => starts_at
+                    .checked_add_signed(Duration::days(1))
+                    .ok_or(ScheduleError::OutOfRange("ends_at"))?,
+            };
+            (starts_at, ends_at)
+        }
+        (None, None) => return Err(ScheduleError::MissingStart),
+    };
+    let doors_open_at = match input.doors_open_at {
+        Some(doors) => parse_doors(doors, timezone)?,
+        None => None,
+    };
+
+    LocalSchedule {
+        timezone,
+        starts_at,
+        ends_at,
+        doors_open_at,
+    }
+    .resolve()
+}
+
+/// Applies changes to an event's schedule. Changing the timezone keeps the
+/// wall-clock times. A new start or date moves the whole event, unless a new
+/// end or door time is given too.
+pub fn update_schedule(
+    current: EventSchedule,
+    input: ScheduleInput<'_>,
+) -> Result<EventSchedule, ScheduleError> {
+    let mut local = current.to_local();
+    if let Some(timezone) = input.timezone {
+        local.timezone = parse_timezone(timezone)?;
+    }
+    if let Some(starts_at) = input.starts_at {
+        local.move_to(parse_local_time(starts_at, local.timezone, "starts_at")?)?;
+    } else if let Some(date) = input.event_date {
+        let date = parse_date(date)?;
+        local.move_to(date.and_time(local.starts_at.time()))?;
+    }
+    if let Some(ends_at) = input.ends_at {
+        local.ends_at = parse_local_time(ends_at, local.timezone, "ends_at")?;
+    }
+    if let Some(doors) = input.doors_open_at {
+        local.doors_open_at = parse_doors(doors, local.timezone)?;
+    }
+    local.resolve()
+}
+
+impl Event {
+    pub fn schedule(&self) -> EventSchedule {
+        EventSchedule {
+            timezone: self.timezone.parse().unwrap_or(Tz::UTC),
+            starts_at: self.starts_at,
+            ends_at: self.ends_at,
+            doors_open_at: self.doors_open_at,
+        }
+    }
+}
+
+impl NewEvent {
+    pub fn schedule(&self) -> ScheduleInput<'_> {
+        ScheduleInput {
+            event_date: self.event_date.as_deref(),
+            starts_at: self.starts_at.as_deref(),
+            ends_at: self.ends_at.as_deref(),
+            doors_open_at: self.doors_open_at.as_deref(),
+            timezone: self.timezone.as_deref(),
+        }
+    }
+}
+
+impl EventUpdate {
+    pub fn schedule(&self) -> ScheduleInput<'_> {
+        ScheduleInput {
+            event_date: self.event_date.as_deref(),
+            starts_at: self.starts_at.as_deref(),
+            ends_at: self.ends_at.as_deref(),
+            doors_open_at: self.doors_open_at.as_deref(),
+            timezone: self.timezone.as_deref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::ticket_credential::{
+        issue_ticket_credential, verify_ticket_credential, TicketCredential,
+    };
+    use uuid::Uuid;
+
+    const LAST_DAY: &str = "+262142-12-31";
+
+    fn schedule() -> EventSchedule {
+        new_schedule(ScheduleInput {
+            starts_at: Some("2026-12-01T19:00"),
+            ends_at: Some("2026-12-01T22:00"),
+            doors_open_at: Some("2026-12-01T18:00"),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn whole_day_past_the_last_date_is_out_of_range() {
+        let input = ScheduleInput {
+            event_date: Some(LAST_DAY),
+            ..Default::default()
+        };
+        assert_eq!(
+            new_schedule(input).unwrap_err().to_string(),
+            ScheduleError::OutOfRange("ends_at").to_string()
+        );
+    }
+
+    #[test]
+    fn moving_past_the_last_date_is_out_of_range() {
+        let starts_at = format!("{}T23:00", LAST_DAY);
+        let input = ScheduleInput {
+            starts_at: Some(&starts_at),
+            ..Default::default()
+        };
+        assert_eq!(
+            update_schedule(schedule(), input).unwrap_err().to_string(),
+            ScheduleError::OutOfRange("ends_at").to_string()
+        );
+    }
+
+    #[test]
+    fn credentials_issue_for_events_ending_on_the_last_date() {
+        let starts_at = format!("{}T20:00", LAST_DAY);
+        let ends_at = format!("{}T23:00", LAST_DAY);
+        let schedule = new_schedule(ScheduleInput {
+            starts_at: Some(&starts_at),
+            ends_at: Some(&ends_at),
+            ..Default::default()
+        })
+        .unwrap();
+        let keyring = Keyring::for_tests();
+        let credential = TicketCredential {
+            booking_id: Uuid::new_v4(),
+            admission_id: Uuid::new_v4(),
+            event_id: Uuid::new_v4(),
+            holder: Uuid::new_v4(),
+            ticket_id: Uuid::new_v4(),
+            seat: None,
+        };
+
+        let token = issue_ticket_credential(&keyring, &credential, schedule.ends_at).unwrap();
+        let verified = verify_ticket_credential(&keyring, &token).unwrap();
+        assert_eq!(verified.admission_id, credential.admission_id);
+    }
+
+    #[test]
+    fn moving_keeps_length_and_doors() {
+        let input = ScheduleInput {
+            event_date: Some("2027-01-15"),
+            ..Default::default()
+        };
+        let moved = update_schedule(schedule(), input).unwrap();
+        assert_eq!(moved.starts_at.to_rfc3339(), "2027-01-15T19:00:00+00:00");
+        assert_eq!(moved.ends_at.to_rfc3339(), "2027-01-15T22:00:00+00:00");
+        assert_eq!(
+            moved.doors_open_at.unwrap().to_rfc3339(),
+            "2027-01-15T18:00:00+00:00"
+        );
+    }
+}
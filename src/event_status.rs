@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::Event;
+
+// Where an event is in its lifecycle, stored in events.event_status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStatus {
+    Draft,
+    Published,
+    SalesPaused,
+    Cancelled,
+    Postponed,
+    Completed,
+}
+
+impl EventStatus {
+    pub const ALL: [EventStatus; 6] = [
+        EventStatus::Draft,
+        EventStatus::Published,
+        EventStatus::SalesPaused,
+        EventStatus::Cancelled,
+        EventStatus::Postponed,
+        EventStatus::Completed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventStatus::Draft => "draft",
+            EventStatus::Published => "published",
+            EventStatus::SalesPaused => "sales_paused",
+            EventStatus::Cancelled => "cancelled",
+            EventStatus::Postponed => "postponed",
+            EventStatus::Completed => "completed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        EventStatus::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == status)
+    }
+
+    // How the status reads in emails to attendees
+    pub fn label(&self) -> &'static str {
+        match self {
+            EventStatus::Draft => "Draft",
+            EventStatus::Published => "On",
+            EventStatus::SalesPaused => "On, ticket sales paused",
+            EventStatus::Cancelled => "Cancelled",
+            EventStatus::Postponed => "Postponed",
+            EventStatus::Completed => "Over",
+        }
+    }
+
+    /// The only place that decides which status changes are allowed.
+    /// Cancelled and completed events stay that way.
+    pub fn can_become(&self, next: EventStatus) -> bool {
+        use EventStatus::*;
+        matches!(
+            (self, next),
+            (Draft, Published | Cancelled)
+                | (Published, SalesPaused | Cancelled | Postponed | Completed)
+                | (SalesPaused, Published | Cancelled | Postponed | Completed)
+                | (Postponed, Published | Cancelled)
+        )
+    }
+
+    /// Statuses an event can be moved to `next` from.
+    pub fn able_to_become(next: EventStatus) -> Vec<&'static str> {
+        EventStatus::ALL
+            .into_iter()
+            .filter(|status| status.can_become(next))
+            .map(|status| status.as_str())
+            .collect()
+    }
+
+    // Shown in the public event list. Pausing sales doesn't unpublish an event.
+    pub fn is_listed(&self) -> bool {
+        matches!(self, EventStatus::Published | EventStatus::SalesPaused)
+    }
+
+    pub fn on_sale(&self) -> bool {
+        *self == EventStatus::Published
+    }
+
+    // Details of cancelled and finished events are kept as they were
+    pub fn accepts_edits(&self) -> bool {
+        !matches!(self, EventStatus::Cancelled | EventStatus::Completed)
+    }
+}
+
+impl Event {
+    pub fn status(&self) -> EventStatus {
+        EventStatus::parse(&self.event_status).unwrap_or(EventStatus::Draft)
+    }
+}
@@ -0,0 +1,183 @@
+use crate::handler::booking_handler::require_holder_or_organizer;
+use crate::jwt_auth;
+use crate::models::{Admission, AdmissionUpdate, AppState, CredentialQuery};
+use crate::ticket_credential::{issue_ticket_credential, render_qr, QrFormat, TicketCredential};
+use actix_web::{
+    get, patch,
+    web::{Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+// The people admitted on a booking and whether each is checked in
+#[get("/api/v1/bookings/{booking_id}/admissions")]
+async fn get_admissions(
+    booking_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let booking_id = booking_id.into_inner();
+    if let Err(response) = require_holder_or_organizer(&pool, &jwt_guard.user, booking_id).await {
+        return response;
+    }
+
+    match sqlx::query_as!(
+        Admission,
+        "SELECT * FROM admissions WHERE booking_id = $1 ORDER BY seq",
+        booking_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(admissions) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": admissions
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        })),
+    }
+}
+
+// Names the person an admission is for
+#[patch("/api/v1/bookings/{booking_id}/admissions/{admission_id}")]
+async fn update_admission(
+    path: Path<(Uuid, Uuid)>,
+    body: Json<AdmissionUpdate>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let (booking_id, admission_id) = path.into_inner();
+    if let Err(response) = require_holder_or_organizer(&pool, &jwt_guard.user, booking_id).await {
+        return response;
+    }
+    let holder_name = body.holder_name.trim();
+    if holder_name.is_empty() || holder_name.chars().count() > 200 {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Holder name must be 1 to 200 characters"
+        }));
+    }
+
+    // Once someone has come in on an admission it can't be handed to someone else
+    match sqlx::query_as!(
+        Admission,
+        "UPDATE admissions SET holder_name = $1
+        WHERE admission_id = $2 AND booking_id = $3 AND NOT verified
+        RETURNING *",
+        holder_name,
+        admission_id,
+        booking_id
+    )
+    .fetch_optional(&pool.db)
+    .await
+    {
+        Ok(Some(admission)) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": admission
+        })),
+        Ok(None) => HttpResponse::Conflict().json(json!({
+            "status": "fail",
+            "error": "Admission not found or already checked in"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        })),
+    }
+}
+
+// Signed credential for one admission, as a QR code or the bare token
+#[get("/api/v1/bookings/{booking_id}/admissions/{admission_id}/credential")]
+async fn get_admission_credential(
+    path: Path<(Uuid, Uuid)>,
+    query: Query<CredentialQuery>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let (booking_id, admission_id) = path.into_inner();
+    let format = match query.format.as_deref() {
+        None | Some("svg") => Some(QrFormat::Svg),
+        Some("png") => Some(QrFormat::Png),
+        Some("jws") => None,
+        Some(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": "Format must be svg, png or jws"
+            }))
+        }
+    };
+    if let Err(response) = require_holder_or_organizer(&pool, &jwt_guard.user, booking_id).await {
+        return response;
+    }
+
+    let admission = match sqlx::query!(
+        "SELECT b.user_id, b.ticket_id AS \"ticket_id!\", t.event_id AS \"event_id!\",
+            t.ticket_type, e.ends_at
+        FROM admissions a
+        JOIN bookings b ON b.booking_id = a.booking_id
+        JOIN tickets t ON t.ticket_id = b.ticket_id
+        JOIN events e ON e.event_id = t.event_id
+        WHERE a.admission_id = $1 AND a.booking_id = $2",
+        admission_id,
+        booking_id
+    )
+    .fetch_optional(&pool.db)
+    .await
+    {
+        Ok(Some(admission)) => admission,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(json!({
+                "status": "fail",
+                "error": "Admission not found"
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({ "error": err.to_string(), "status": "fail" }))
+        }
+    };
+    let Some(holder) = admission.user_id else {
+        return HttpResponse::NotFound().json(json!({
+            "status": "fail",
+            "error": "Booking has no holder"
+        }));
+    };
+
+    let credential = TicketCredential {
+        booking_id,
+        admission_id,
+        event_id: admission.event_id,
+        holder,
+        ticket_id: admission.ticket_id,
+        seat: admission.ticket_type,
+    };
+    let token = match issue_ticket_credential(&pool.keyring, &credential, admission.ends_at) {
+        Ok(token) => token,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Failed to sign ticket credential: {}", err)
+            }))
+        }
+    };
+
+    let Some(format) = format else {
+        return HttpResponse::Ok().json(json!({
+            "status": "success",
+            "credential": token
+        }));
+    };
+    match render_qr(&token, format) {
+        Ok((content_type, image)) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("Cache-Control", "private, no-store"))
+            .body(image),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": format!("Failed to render QR code: {}", err)
+        })),
+    }
+}
@@ -0,0 +1,243 @@
+use crate::api_key::{generate_key, scopes, ApiPermission};
+use crate::jwt_auth;
+use crate::models::{ApiKey, AppState, NewApiKey};
+use crate::refresh_token::hash_token;
+use crate::roles::{require_event_role, ORGANIZERS};
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+// The api_keys.name column's length
+const MAX_NAME_LENGTH: usize = 100;
+
+fn key_json(key: &ApiKey) -> serde_json::Value {
+    json!({
+        "key_id" : key.key_id,
+        "name" : key.name,
+        "prefix" : key.prefix,
+        "scopes" : scopes(&key.permissions, key.event_id),
+        "created_by" : key.created_by,
+        "created_at" : key.created_at,
+        "expires_at" : key.expires_at,
+        "last_used_at" : key.last_used_at,
+        "revoked_at" : key.revoked_at
+    })
+}
+
+#[post("/api/v1/events/{event_id}/api-keys")]
+async fn create_api_key(
+    event_id: Path<Uuid>,
+    data: Json<NewApiKey>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+
+    let data = data.into_inner();
+    if data.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Name must not be empty"
+        }));
+    }
+    if data.name.trim().chars().count() > MAX_NAME_LENGTH {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : format!("Name must be at most {} characters", MAX_NAME_LENGTH)
+        }));
+    }
+    let mut permissions = Vec::new();
+    for permission in &data.permissions {
+        match ApiPermission::parse(permission) {
+            Some(permission) if !permissions.contains(&permission.as_str().to_string()) => {
+                permissions.push(permission.as_str().to_string())
+            }
+            Some(_) => {}
+            None => {
+                return HttpResponse::BadRequest().json(json!({
+                    "status" : "fail",
+                    "error" : format!("Unknown permission {}", permission)
+                }))
+            }
+        }
+    }
+    if permissions.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "At least one permission is required"
+        }));
+    }
+    let expires_at = match data.expires_in_days {
+        Some(days) if days > 0 => {
+            match chrono::Duration::try_days(days)
+                .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl))
+            {
+                Some(expires_at) => Some(expires_at.naive_utc()),
+                None => {
+                    return HttpResponse::BadRequest().json(json!({
+                        "status" : "fail",
+                        "error" : "expires_in_days is too large"
+                    }))
+                }
+            }
+        }
+        Some(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "expires_in_days must be positive"
+            }))
+        }
+        None => None,
+    };
+
+    let (key, prefix) = generate_key();
+    match sqlx::query_as!(
+        ApiKey,
+        "INSERT INTO api_keys (key_id, event_id, created_by, name, prefix, key_hash, permissions, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING key_id, event_id, created_by, name, prefix, permissions, created_at, expires_at, last_used_at, revoked_at",
+        Uuid::new_v4(),
+        event_id,
+        jwt_guard.user.user_id,
+        data.name.trim(),
+        prefix,
+        hash_token(&key),
+        &permissions,
+        expires_at
+    )
+    .fetch_one(&pool.db)
+    .await
+    {
+        // The key itself is only ever shown here
+        Ok(api_key) => {
+            let mut body = key_json(&api_key);
+            body["key"] = json!(key);
+            HttpResponse::Ok().json(json!({
+                "status" : "success",
+                "data" : body
+            }))
+        }
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[get("/api/v1/events/{event_id}/api-keys")]
+async fn get_api_keys(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+
+    match sqlx::query_as!(
+        ApiKey,
+        "SELECT key_id, event_id, created_by, name, prefix, permissions, created_at, expires_at, last_used_at, revoked_at
+        FROM api_keys WHERE event_id = $1
+        ORDER BY created_at DESC",
+        event_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(keys) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : keys.iter().map(key_json).collect::<Vec<_>>()
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[delete("/api/v1/events/{event_id}/api-keys/{key_id}")]
+async fn revoke_api_key(
+    path: Path<(Uuid, Uuid)>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let (event_id, key_id) = path.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+
+    match sqlx::query!(
+        "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, NOW())
+        WHERE key_id = $1 AND event_id = $2",
+        key_id,
+        event_id
+    )
+    .execute(&pool.db)
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => HttpResponse::NotFound().json(json!({
+            "status" : "fail",
+            "error" : "API key not found"
+        })),
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::Role;
+    use crate::test_support::{access_token, create_event, create_user, delete_users, test_state};
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn rejects_out_of_range_input() {
+        let state = test_state().await;
+        let organizer = create_user(&state, Role::Organizer).await;
+        let event_id = create_event(&state, &organizer).await;
+        let token = access_token(&state, &organizer).await;
+        let app =
+            test::init_service(App::new().app_data(state.clone()).service(create_api_key)).await;
+        let create = |name: String, expires_in_days: i64| {
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/events/{}/api-keys", event_id))
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(json!({
+                    "name": name,
+                    "permissions": ["verify"],
+                    "expires_in_days": expires_in_days
+                }))
+                .to_request()
+        };
+
+        for (name, expires_in_days) in [
+            ("Scanner".to_string(), i64::MAX),
+            ("Scanner".to_string(), 1_000_000_000),
+            ("x".repeat(MAX_NAME_LENGTH + 1), 30),
+        ] {
+            let response = test::call_service(&app, create(name, expires_in_days)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = test::call_service(&app, create("x".repeat(MAX_NAME_LENGTH), 30)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        delete_users(&state, &[&organizer]).await;
+    }
+}
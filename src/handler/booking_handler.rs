@@ -1,14 +1,26 @@
-use crate::models::{Booking, NewBooking};
+use crate::api_key::{ApiPermission, Caller};
+use crate::check_in::{
+    admit, booking_progress, validate_credential, DenyReason, ScanError, ScanOutcome, Scanner,
+};
+use crate::email_verification::{require_verified_email, GatedAction};
+use crate::event_status::EventStatus;
+use crate::models::{Admission, Booking, NewBooking, PageQuery, ScanContext, TicketScan, User};
+use crate::money::Money;
+use crate::pagination::{Cursor, Page};
+use crate::roles::{require_event_role, DOOR_STAFF, ORGANIZERS};
 use crate::{jwt_auth, AppState};
 use actix_web::{
-    delete, get, patch, post,
-    web::{Data, Json, Path},
+    delete, post, routes,
+    web::{Data, Json, Path, Query},
     HttpResponse, Responder,
 };
+use chrono::NaiveDateTime;
 use serde_json::json;
 use uuid::Uuid;
 
-#[post("/book_ticket")]
+#[routes]
+#[post("/api/v1/bookings")]
+#[post("/book_ticket")] // legacy
 async fn book_ticket(
     booking: Json<NewBooking>,
     pool: Data<AppState>,
@@ -16,75 +28,258 @@ async fn book_ticket(
 ) -> HttpResponse {
     let booking = booking.into_inner();
     let user_id = jwt_guard.user.user_id;
+    if let Err(response) = require_verified_email(&jwt_guard.user, GatedAction::BookTicket) {
+        return response;
+    }
 
-    // Parse price to an integer
-    let price: i32 = booking.price.parse().unwrap_or(0);
     let quantity: i32 = booking.quantity.parse().unwrap_or(0);
-    // Calculate total price if both quantity and price are present
-    let total_price = quantity * price;
+    if quantity <= 0 {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Quantity must be a positive number"
+        }));
+    }
+    let holder_names = booking.holder_names.unwrap_or_default();
+    if holder_names.len() > quantity as usize {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "More holder names than tickets booked"
+        }));
+    }
+    if holder_names
+        .iter()
+        .any(|name| name.trim().chars().count() > 200)
+    {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Holder names must be at most 200 characters"
+        }));
+    }
     let booking_id = Uuid::new_v4();
 
+    // The booking row and the availability decrement must succeed or fail together
+    let mut tx = match pool.db.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Failed to start transaction: {}", err)
+            }))
+        }
+    };
+
+    // Lock the ticket row so concurrent bookings queue up behind this one.
+    // Price and event name come from the database, never from the client.
+    let ticket = match sqlx::query!(
+        "SELECT t.availability, t.price, t.currency, e.event_name AS \"event_name?\",
+            e.event_status AS \"event_status?\"
+        FROM tickets t
+        LEFT JOIN events e ON e.event_id = t.event_id
+        WHERE t.ticket_id = $1
+        FOR UPDATE OF t",
+        booking.ticket_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(ticket)) => ticket,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(json!({
+                "status": "fail",
+                "error": "Ticket not found"
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Failed to check ticket availability: {}", err)
+            }))
+        }
+    };
+
+    if let Some(status) = ticket.event_status.as_deref().and_then(EventStatus::parse) {
+        if !status.on_sale() {
+            return HttpResponse::Conflict().json(json!({
+                "status": "fail",
+                "code": "not_on_sale",
+                "error": "Tickets for this event are not on sale",
+                "event_status": status
+            }));
+        }
+    }
+
+    let unit_price = match Money::new(ticket.price, &ticket.currency) {
+        Ok(price) => price,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Ticket has an invalid price: {}", err)
+            }))
+        }
+    };
+    if let Some(quoted) = booking.price {
+        if quoted != unit_price.amount {
+            return HttpResponse::Conflict().json(json!({
+                "status": "fail",
+                "code": "price_mismatch",
+                "error": "Ticket price has changed, please refresh",
+                "price": unit_price
+            }));
+        }
+    }
+
+    let availability = ticket.availability.unwrap_or(0);
+    if availability < quantity {
+        let error = if availability <= 0 {
+            "Sold out".to_string()
+        } else {
+            format!("Only {} left", availability)
+        };
+        return HttpResponse::Conflict().json(json!({
+            "status": "fail",
+            "code": "sold_out",
+            "error": error,
+            "available": availability.max(0)
+        }));
+    }
+
+    let total_price = match unit_price.times(quantity) {
+        Some(total) => total,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": "Total price is too large"
+            }))
+        }
+    };
+
     let query_res = sqlx::query_as!(
         Booking,
-        "INSERT INTO bookings (booking_id, event_name, ticket_id, user_id, quantity, total_price,verified)
-        VALUES ($1, $2, $3, $4, $5, $6,$7)
-        RETURNING *",
+        "INSERT INTO bookings (booking_id, event_name, ticket_id, user_id, quantity, total_price, currency, verified)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING *, 0::BIGINT AS \"checked_in!\"",
         booking_id,
-        booking.event_name,
+        ticket.event_name,
         booking.ticket_id,
         user_id,
-        booking.quantity,
-        total_price.to_string(),
+        quantity,
+        total_price.amount,
+        total_price.currency,
         false
     )
-    .fetch_one(&pool.db)
+    .fetch_one(&mut *tx)
     .await;
 
-    match query_res {
-        Ok(data) => {
-            match sqlx::query!(
-                "UPDATE tickets SET availability = availability - $1 WHERE ticket_id = $2",
-                quantity, // Use quantity directly for subtraction
-                data.ticket_id
-            )
-            .execute(&pool.db)
-            .await
-            {
-                Ok(_) => HttpResponse::Ok().json(json!({
-                    "status": "success",
-                    "data": data
-                })),
-                Err(err) => HttpResponse::InternalServerError().json(json!({
-                    "status": "fail",
-                    "error": format!("Failed to update ticket availability: {}", err)
-                })),
-            }
+    let data = match query_res {
+        Ok(data) => data,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": format!("Failed to book ticket: {}", err)
+            }))
         }
-        Err(err) => HttpResponse::BadRequest().json(json!({
+    };
+
+    // One admission per person, each checked in on its own
+    let admissions = match sqlx::query_as!(
+        Admission,
+        "INSERT INTO admissions (admission_id, booking_id, seq, holder_name)
+        SELECT gen_random_uuid(), $1, n, NULLIF(TRIM(($2::TEXT[])[n]), '')
+        FROM generate_series(1, $3) AS n
+        RETURNING *",
+        booking_id,
+        &holder_names,
+        quantity
+    )
+    .fetch_all(&mut *tx)
+    .await
+    {
+        Ok(admissions) => admissions,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Failed to book ticket: {}", err)
+            }))
+        }
+    };
+
+    if let Err(err) = sqlx::query!(
+        "UPDATE tickets SET availability = availability - $1 WHERE ticket_id = $2",
+        quantity, // Use quantity directly for subtraction
+        data.ticket_id
+    )
+    .execute(&mut *tx)
+    .await
+    {
+        // Dropping the transaction rolls back the booking row
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": format!("Failed to update ticket availability: {}", err)
+        }));
+    }
+
+    match tx.commit().await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": data,
+            "admissions": admissions
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
             "status": "fail",
             "error": format!("Failed to book ticket: {}", err)
         })),
     }
 }
 
-#[get("/bookings")]
+#[routes]
+#[get("/api/v1/bookings")]
+#[get("/bookings")] // legacy
 pub async fn get_bookings(
+    query: Query<PageQuery>,
     pool: Data<AppState>,
     jwt_guard: jwt_auth::JwtMiddleware,
 ) -> impl Responder {
     let user_id = jwt_guard.user.user_id;
+    // Most recent first
+    let page: Page<NaiveDateTime> = match Page::new(query.cursor.as_deref(), query.limit) {
+        Ok(page) => page,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
+            }))
+        }
+    };
     match sqlx::query_as!(
         Booking,
-        "Select * from bookings where user_id = $1",
-        user_id
+        "SELECT b.*, (
+            SELECT COUNT(*) FROM admissions a WHERE a.booking_id = b.booking_id AND a.verified
+        ) AS \"checked_in!\"
+        FROM bookings b
+        WHERE b.user_id = $1
+            AND ($2::TIMESTAMP IS NULL
+                OR (COALESCE(b.booking_date, 'epoch'), b.booking_id) < ($2, $3))
+        ORDER BY COALESCE(b.booking_date, 'epoch') DESC, b.booking_id DESC
+        LIMIT $4",
+        user_id,
+        page.after_key(),
+        page.after_id(),
+        page.fetch_limit()
     )
     .fetch_all(&pool.db)
     .await
     {
-        Ok(tickets) => HttpResponse::Ok().json(json!({
-            "status" : "success",
-            "data" : tickets,
-        })),
+        Ok(bookings) => {
+            let (bookings, next_cursor) = page.finish(bookings, |booking| Cursor {
+                key: booking.booking_date.unwrap_or_default(),
+                id: booking.booking_id,
+            });
+            HttpResponse::Ok().json(json!({
+                "status" : "success",
+                "data" : bookings,
+                "next_cursor" : next_cursor,
+            }))
+        }
         Err(err) => HttpResponse::InternalServerError().json(json!({
             "status" : "fail",
             "error" : err.to_string()
@@ -92,31 +287,204 @@ pub async fn get_bookings(
     }
 }
 
-#[patch("/booking_verification/{booking_id}")]
-async fn ticket_verification(booking_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
-    let booking_id = booking_id.into_inner();
-
-    let result = sqlx::query_as!(
-        Booking,
-        "UPDATE bookings SET verified = TRUE WHERE booking_id = $1 AND verified = FALSE RETURNING *",
+// Looks up who holds a booking and which event it is for
+pub async fn booking_holder_and_event(
+    pool: &AppState,
+    booking_id: Uuid,
+) -> Result<(Option<Uuid>, Uuid), HttpResponse> {
+    match sqlx::query!(
+        "SELECT b.user_id, t.event_id AS \"event_id?\"
+        FROM bookings b
+        LEFT JOIN tickets t ON t.ticket_id = b.ticket_id
+        WHERE b.booking_id = $1",
         booking_id
     )
     .fetch_optional(&pool.db)
+    .await
+    {
+        Ok(Some(booking)) => match booking.event_id {
+            Some(event_id) => Ok((booking.user_id, event_id)),
+            None => Err(HttpResponse::NotFound().json(json!({
+                "status": "fail",
+                "error": "Booking is not linked to an event"
+            }))),
+        },
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "status": "fail",
+            "error": "Booking not found"
+        }))),
+        Err(err) => Err(HttpResponse::InternalServerError()
+            .json(json!({ "error": err.to_string(), "status": "fail" }))),
+    }
+}
+
+/// Lets the booking's holder through, or an organizer of its event.
+pub async fn require_holder_or_organizer(
+    pool: &AppState,
+    user: &User,
+    booking_id: Uuid,
+) -> Result<(), HttpResponse> {
+    let (holder, event_id) = booking_holder_and_event(pool, booking_id).await?;
+    if holder == Some(user.user_id) {
+        return Ok(());
+    }
+    require_event_role(&pool.db, user, event_id, ORGANIZERS).await
+}
+
+#[routes]
+#[post("/api/v1/bookings/{booking_id}/check-in")]
+#[patch("/booking_verification/{booking_id}")] // legacy
+async fn ticket_verification(
+    booking_id: Path<Uuid>,
+    context: Query<ScanContext>,
+    pool: Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    let booking_id = booking_id.into_inner();
+
+    let event_id = match booking_holder_and_event(&pool, booking_id).await {
+        Ok((_, event_id)) => event_id,
+        Err(response) => return response,
+    };
+    if let Err(response) = caller
+        .require(&pool.db, event_id, DOOR_STAFF, ApiPermission::Verify)
+        .await
+    {
+        return response;
+    }
+
+    // Each check-in admits one person; groups are scanned once per person
+    check_in(&pool, event_id, booking_id, None, &caller, &context).await
+}
+
+// Scans an admission in or out and reports the booking's progress. Scans the
+// event's entry policy turns away are reported, not repeated.
+async fn check_in(
+    pool: &AppState,
+    event_id: Uuid,
+    booking_id: Uuid,
+    admission_id: Option<Uuid>,
+    caller: &Caller,
+    context: &ScanContext,
+) -> HttpResponse {
+    let too_long = |value: &Option<String>| {
+        value
+            .as_ref()
+            .is_some_and(|value| value.chars().count() > 100)
+    };
+    if too_long(&context.gate) || too_long(&context.device_id) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Gate and device id must be at most 100 characters"
+        }));
+    }
+
+    let direction = context.direction.unwrap_or_default();
+    let scanner = Scanner::new(caller, context.gate.as_deref());
+    let outcome = admit(
+        &pool.db,
+        event_id,
+        booking_id,
+        admission_id,
+        direction,
+        &scanner,
+        context.device_id.as_deref(),
+    )
     .await;
+    let admission = match outcome {
+        Ok(ScanOutcome::Admitted(admission)) => admission,
+        Ok(ScanOutcome::Denied(reason)) => {
+            let body = json!({
+                "status": "fail",
+                "code": reason.code(),
+                "error": reason.to_string()
+            });
+            return match reason {
+                DenyReason::NotInside => HttpResponse::Conflict().json(body),
+                _ => HttpResponse::AlreadyReported().json(body),
+            };
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .json(json!({ "error": err.to_string(), "status": "fail" }))
+        }
+    };
 
-    match result {
-        Ok(Some(_)) => HttpResponse::Ok().json(json!({ "status": "success" })),
-        Ok(None) => HttpResponse::AlreadyReported()
-            .json(json!({ "error": "Already scanned or not valid ",
-                "status": "fail" })),
+    match booking_progress(&pool.db, booking_id).await {
+        Ok((checked_in, quantity)) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "direction": direction,
+            "data": admission,
+            "checked_in": checked_in,
+            "quantity": quantity
+        })),
         Err(err) => HttpResponse::InternalServerError()
             .json(json!({ "error": err.to_string(), "status": "fail" })),
     }
 }
 
-#[delete("/delete/{booking_id}")]
-async fn delete_booking(booking_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
-    let booking_id = booking_id.into_inner();
+// Door check-in from a scanned credential rather than a bare booking id
+#[post("/api/v1/events/{event_id}/check-in")]
+async fn scan_ticket_credential(
+    event_id: Path<Uuid>,
+    scan: Json<TicketScan>,
+    pool: Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = caller
+        .require(&pool.db, event_id, DOOR_STAFF, ApiPermission::Verify)
+        .await
+    {
+        return response;
+    }
+
+    // The booking may also have been cancelled or changed since the credential was issued
+    let credential =
+        match validate_credential(&pool.db, &pool.keyring, event_id, &scan.credential).await {
+            Ok(credential) => credential,
+            Err(err) => {
+                let body = json!({
+                    "status": "fail",
+                    "code": err.code(),
+                    "error": err.to_string()
+                });
+                return match err {
+                    ScanError::InvalidCredential => HttpResponse::BadRequest().json(body),
+                    ScanError::WrongEvent | ScanError::Superseded => {
+                        HttpResponse::Conflict().json(body)
+                    }
+                    ScanError::Database(_) => HttpResponse::InternalServerError().json(body),
+                };
+            }
+        };
+
+    check_in(
+        &pool,
+        event_id,
+        credential.booking_id,
+        Some(credential.admission_id),
+        &caller,
+        &scan.context,
+    )
+    .await
+}
+
+#[delete("/api/v1/bookings/{booking_id}")]
+async fn delete_booking(
+    booking_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    remove_booking(&pool, &jwt_guard.user, booking_id.into_inner()).await
+}
+
+pub async fn remove_booking(pool: &AppState, user: &User, booking_id: Uuid) -> HttpResponse {
+    // Holders can cancel their own bookings, organizers any booking for their event
+    if let Err(response) = require_holder_or_organizer(pool, user, booking_id).await {
+        return response;
+    }
+
     match sqlx::query!("DELETE FROM bookings where booking_id = $1", booking_id)
         .execute(&pool.db)
         .await
@@ -130,3 +498,78 @@ async fn delete_booking(booking_id: Path<Uuid>, pool: Data<AppState>) -> impl Re
         })),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::Role;
+    use crate::test_support::{
+        access_token, create_event, create_ticket, create_user, delete_users, test_state,
+    };
+    use actix_web::{http::StatusCode, test, App};
+    use futures::future::join_all;
+
+    #[actix_web::test]
+    async fn parallel_bookings_never_oversell() {
+        const SEATS: i32 = 3;
+        const BUYERS: usize = 12;
+        let state = test_state().await;
+        let organizer = create_user(&state, Role::Organizer).await;
+        let event_id = create_event(&state, &organizer).await;
+        let ticket_id = create_ticket(&state, event_id, SEATS).await;
+        let mut buyers = Vec::new();
+        for _ in 0..BUYERS {
+            let buyer = create_user(&state, Role::Attendee).await;
+            let token = access_token(&state, &buyer).await;
+            buyers.push((buyer, token));
+        }
+        let app = test::init_service(App::new().app_data(state.clone()).service(book_ticket)).await;
+
+        let responses = join_all(buyers.iter().map(|(_, token)| {
+            let req = test::TestRequest::post()
+                .uri("/api/v1/bookings")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(json!({ "ticket_id": ticket_id, "quantity": "1" }))
+                .to_request();
+            test::call_service(&app, req)
+        }))
+        .await;
+
+        let mut sold = 0;
+        let mut sold_out = 0;
+        for response in responses {
+            match response.status() {
+                StatusCode::OK => sold += 1,
+                StatusCode::CONFLICT => {
+                    let body: serde_json::Value = test::read_body_json(response).await;
+                    assert_eq!(body["code"], "sold_out");
+                    sold_out += 1;
+                }
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        assert_eq!(sold, SEATS);
+        assert_eq!(sold_out, BUYERS as i32 - SEATS);
+
+        let availability = sqlx::query_scalar!(
+            "SELECT availability FROM tickets WHERE ticket_id = $1",
+            ticket_id
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        assert_eq!(availability, Some(0));
+        let booked = sqlx::query_scalar!(
+            "SELECT COALESCE(SUM(quantity), 0) FROM bookings WHERE ticket_id = $1",
+            ticket_id
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        assert_eq!(booked, Some(SEATS as i64));
+
+        let mut users: Vec<&User> = buyers.iter().map(|(buyer, _)| buyer).collect();
+        users.push(&organizer);
+        delete_users(&state, &users).await;
+    }
+}
@@ -1,43 +1,116 @@
 use crate::{
+    email_verification::{require_verified_email, GatedAction},
+    event_changes::{diff_events, notify_attendees, record_event_changes, FieldChange},
+    event_listing::{event_page, list_events, search_hits},
+    event_schedule::{new_schedule, update_schedule, ScheduleInput},
+    event_status::EventStatus,
     jwt_auth,
-    models::{AppState, Event, NewEvent},
+    models::{
+        AppState, Event, EventChange, EventMember, EventQuery, EventUpdate, NewEvent,
+        NewEventMember, PageQuery, User,
+    },
+    pagination::{Cursor, Page},
+    roles::{require_event_role, EventRole, Organizer, RequireRole, Role, ORGANIZERS},
 };
 use actix_web::{
-    delete, get, post,
-    web::{Data, Json, Path},
+    delete, get, patch, post, routes,
+    web::{Data, Json, Path, Query},
     HttpResponse, Responder,
 };
-use chrono::{NaiveDate, Utc};
+use chrono::{DateTime, Utc};
 use serde_json::json;
+use sqlx::{postgres::Postgres, Pool};
 use uuid::Uuid;
 
 // Handler for the create_user route
-#[post("/events/add_event")]
+#[routes]
+#[post("/api/v1/events")]
+#[post("/events/add_event")] // legacy
 async fn create_event(
-    jwt_guard: jwt_auth::JwtMiddleware,
+    jwt_guard: RequireRole<Organizer>,
     event_data: Json<NewEvent>,
     pool: Data<AppState>,
 ) -> impl Responder {
+    if let Err(response) = require_verified_email(&jwt_guard.user, GatedAction::CreateEvent) {
+        return response;
+    }
     let event = event_data.into_inner();
-    let event_date = NaiveDate::parse_from_str(&event.event_date, "%Y-%m-%d")
-        .expect("Failed to parse event_date");
+    let category = event
+        .category
+        .as_deref()
+        .map(str::trim)
+        .filter(|category| !category.is_empty());
+    if category.is_some_and(|category| category.chars().count() > 50) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Category must be at most 50 characters"
+        }));
+    }
+    let schedule = match new_schedule(event.schedule()) {
+        Ok(schedule) if schedule.ends_at <= Utc::now() => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": "Event would already be over"
+            }))
+        }
+        Ok(schedule) => schedule,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": err.to_string()
+            }))
+        }
+    };
+    let mut tx = match pool.db.begin().await {
+        Ok(tx) => tx,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "Error": err.to_string(),
+            }))
+        }
+    };
     // Execute the SQL query to insert a new event into the database
     let query_res = sqlx::query_as!(
         Event,
-        "INSERT INTO events (event_id ,user_id ,event_name, event_date, event_location, event_description,event_status)
-         VALUES ($1, $2, $3, $4, $5,$6,$7)
+        "INSERT INTO events (event_id ,user_id ,event_name, event_date, event_location, event_description,event_status,
+            starts_at, ends_at, doors_open_at, timezone, category)
+         VALUES ($1, $2, $3, $4, $5,$6,$7, $8, $9, $10, $11, $12)
          RETURNING *",
         Uuid::new_v4(),
         jwt_guard.user.user_id,
         event.event_name,
-        event_date,
+        schedule.event_date(),
         event.event_location,
         event.event_description,
-        false,
+        // Not listed or on sale until the owner publishes it
+        EventStatus::Draft.as_str(),
+        schedule.starts_at,
+        schedule.ends_at,
+        schedule.doors_open_at,
+        schedule.timezone.name(),
+        category,
     )
-    .fetch_one(&pool.db)
+    .fetch_one(&mut *tx)
     .await;
 
+    // The creator becomes the event's owner
+    let query_res = match query_res {
+        Ok(event) => sqlx::query!(
+            "INSERT INTO event_members (event_id, user_id, role) VALUES ($1, $2, $3)",
+            event.event_id,
+            jwt_guard.user.user_id,
+            EventRole::Owner.as_str()
+        )
+        .execute(&mut *tx)
+        .await
+        .map(|_| event),
+        Err(err) => Err(err),
+    };
+    let query_res = match query_res {
+        Ok(event) => tx.commit().await.map(|_| event),
+        Err(err) => Err(err),
+    };
+
     // Handle the query result
     match query_res {
         Ok(event) => HttpResponse::Ok().json(json!(
@@ -57,8 +130,14 @@ async fn create_event(
     }
 }
 
-#[get("/event/{event_id}")]
-async fn get_event(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
+#[routes]
+#[get("/api/v1/events/{event_id}")]
+#[get("/event/{event_id}")] // legacy
+async fn get_event(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: Option<jwt_auth::JwtMiddleware>,
+) -> impl Responder {
     let event_id = event_id.into_inner();
     // let user_id = token_details.user_id;
     let event_data = sqlx::query_as!(
@@ -76,6 +155,26 @@ async fn get_event(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder
     .fetch_one(&pool.db)
     .await;
 
+    // Drafts are only visible to the event's own organizers
+    let event_data = match event_data {
+        Ok(event) if event.status() == EventStatus::Draft => {
+            let organizer = match &jwt_guard {
+                Some(jwt_guard) => {
+                    require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS)
+                        .await
+                        .is_ok()
+                }
+                None => false,
+            };
+            if organizer {
+                Ok(event)
+            } else {
+                Err(sqlx::Error::RowNotFound)
+            }
+        }
+        event_data => event_data,
+    };
+
     match event_data {
         Ok(events) => HttpResponse::Ok().json(json!({
             "status": "success",
@@ -88,13 +187,25 @@ async fn get_event(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder
     }
 }
 
-#[get("/userevents")]
+#[routes]
+#[get("/api/v1/users/me/events")]
+#[get("/userevents")] // legacy
 async fn get_event_by_user(
     jwt_guard: jwt_auth::JwtMiddleware,
+    query: Query<PageQuery>,
     pool: Data<AppState>,
 ) -> impl Responder {
     let user_id = jwt_guard.user.user_id;
-    // let user_id = token_details.user_id;
+    // Soonest to start first
+    let page: Page<DateTime<Utc>> = match Page::new(query.cursor.as_deref(), query.limit) {
+        Ok(page) => page,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": err.to_string()
+            }))
+        }
+    };
     let event_data = sqlx::query_as!(
         Event,
         "
@@ -104,17 +215,30 @@ async fn get_event_by_user(
             events
         WHERE
             user_id = $1
+            AND ($2::TIMESTAMPTZ IS NULL OR (starts_at, event_id) > ($2, $3))
+        ORDER BY starts_at, event_id
+        LIMIT $4
         ",
-        user_id
+        user_id,
+        page.after_key(),
+        page.after_id(),
+        page.fetch_limit()
     )
     .fetch_all(&pool.db)
     .await;
 
     match event_data {
-        Ok(events) => HttpResponse::Ok().json(json!({
-            "status": "success",
-            "data": events
-        })),
+        Ok(events) => {
+            let (events, next_cursor) = page.finish(events, |event| Cursor {
+                key: event.starts_at,
+                id: event.event_id,
+            });
+            HttpResponse::Ok().json(json!({
+                "status": "success",
+                "data": events,
+                "next_cursor": next_cursor
+            }))
+        }
         Err(err) => HttpResponse::NotFound().json(json!({
             "error" : "Event Not Found",
             "system_error" : err.to_string()
@@ -122,36 +246,513 @@ async fn get_event_by_user(
     }
 }
 
-#[get("/events")]
-async fn get_events(pool: Data<AppState>) -> impl Responder {
-    // Query the database to get events with associated tickets
-    let event_data = sqlx::query_as!(
-        Event,
-        "
-        SELECT
-           *
-        FROM
-            events
-        ",
+// The event catalog, a page at a time. See EventQuery for the filters.
+#[routes]
+#[get("/api/v1/events")]
+#[get("/events")] // legacy
+async fn get_events(
+    query: Query<EventQuery>,
+    pool: Data<AppState>,
+    jwt_guard: Option<jwt_auth::JwtMiddleware>,
+) -> impl Responder {
+    event_listing_response(&pool, &query, jwt_guard).await
+}
+
+// Searches the catalog for q, best matches first. Takes the same filters as
+// the catalog, and highlights where each event matched.
+#[get("/api/v1/events/search")]
+async fn search_events(
+    query: Query<EventQuery>,
+    pool: Data<AppState>,
+    jwt_guard: Option<jwt_auth::JwtMiddleware>,
+) -> impl Responder {
+    match query.search() {
+        Some(search) if search.chars().count() > 200 => HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Search must be at most 200 characters"
+        })),
+        Some(_) => event_listing_response(&pool, &query, jwt_guard).await,
+        None => HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Give something to search for in q"
+        })),
+    }
+}
+
+async fn event_listing_response(
+    pool: &AppState,
+    query: &EventQuery,
+    jwt_guard: Option<jwt_auth::JwtMiddleware>,
+) -> HttpResponse {
+    let page = match event_page(query) {
+        Ok(page) => page,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": error
+            }))
+        }
+    };
+    // Published events, plus any the caller organizes or works on. Admins see all.
+    let user_id = jwt_guard.as_ref().map(|jwt_guard| jwt_guard.user.user_id);
+    let admin = jwt_guard
+        .as_ref()
+        .is_some_and(|jwt_guard| jwt_guard.user.account_role() == Role::Admin);
+    let (events, next_cursor) = match list_events(&pool.db, query, &page, user_id, admin).await {
+        Ok(listing) => listing,
+        Err(err) => {
+            return HttpResponse::NotFound().json(json!({
+                "error" : "Event Not Found",
+                "system_error" : err.to_string()
+            }))
+        }
+    };
+    let event_ids: Vec<Uuid> = events.iter().map(|event| event.event_id).collect();
+    let data = match query.search() {
+        Some(search) => search_hits(&pool.db, search, events)
+            .await
+            .map(|hits| json!(hits)),
+        None => Ok(json!(events)),
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": err.to_string()
+            }))
+        }
+    };
+
+    // Anonymous visitors get the plain list
+    let Some(jwt_guard) = jwt_guard else {
+        return HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": data,
+            "next_cursor": next_cursor
+        }));
+    };
+
+    // Logged-in users also learn which of these events they already hold bookings for
+    match sqlx::query_scalar!(
+        "SELECT DISTINCT t.event_id AS \"event_id!\"
+        FROM bookings b
+        JOIN tickets t ON t.ticket_id = b.ticket_id
+        WHERE b.user_id = $1 AND t.event_id = ANY($2)",
+        jwt_guard.user.user_id,
+        &event_ids[..]
     )
     .fetch_all(&pool.db)
-    .await;
-
-    match event_data {
-        Ok(events) => HttpResponse::Ok().json(json!({
+    .await
+    {
+        Ok(booked_event_ids) => HttpResponse::Ok().json(json!({
             "status": "success",
-            "data": events
+            "data": data,
+            "next_cursor": next_cursor,
+            "booked_event_ids": booked_event_ids
         })),
-        Err(err) => HttpResponse::NotFound().json(json!({
-            "error" : "Event Not Found",
-            "system_error" : err.to_string()
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
         })),
     }
 }
 
-#[delete("/delete/{event_id}")]
-async fn delete_event(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
+// Changes some of an event's details. Attendees are emailed when the date or venue moves.
+#[patch("/api/v1/events/{event_id}")]
+async fn update_event(
+    event_id: Path<Uuid>,
+    data: Json<EventUpdate>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) =
+        require_event_role(&pool.db, &jwt_guard.user, event_id, &[EventRole::Owner]).await
+    {
+        return response;
+    }
+
+    let update = data.into_inner();
+    let invalid = |error: &str| {
+        HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : error
+        }))
+    };
+    if update.event_name.is_none()
+        && update.schedule().is_empty()
+        && update.event_location.is_none()
+        && update.event_description.is_none()
+        && update.category.is_none()
+    {
+        return invalid("Nothing to update");
+    }
+    let event_name = update.event_name.as_deref().map(str::trim);
+    if event_name.is_some_and(|name| name.is_empty() || name.chars().count() > 255) {
+        return invalid("Event name must be 1 to 255 characters");
+    }
+    let event_location = update.event_location.as_deref().map(str::trim);
+    if event_location.is_some_and(|location| location.is_empty() || location.chars().count() > 255)
+    {
+        return invalid("Event location must be 1 to 255 characters");
+    }
+    // An empty description clears it
+    let event_description = update
+        .event_description
+        .as_deref()
+        .map(|description| Some(description.trim()).filter(|d| !d.is_empty()));
+    let category = update
+        .category
+        .as_deref()
+        .map(|category| Some(category.trim()).filter(|c| !c.is_empty()));
+    if category.is_some_and(|category| category.is_some_and(|c| c.chars().count() > 50)) {
+        return invalid("Category must be at most 50 characters");
+    }
+
+    let edit = EventEdit {
+        event_name,
+        schedule: update.schedule(),
+        event_location,
+        event_description,
+        category,
+    };
+    let saved = apply_event_update(&pool.db, event_id, jwt_guard.user.user_id, edit).await;
+    saved_event_response(&pool, saved, |status| {
+        format!("A {} event can't be edited", status.as_str())
+    })
+}
+
+// What became of a change to an event
+enum EventSave {
+    Saved(Box<Event>, Vec<FieldChange>),
+    NotFound,
+    // Not allowed while the event has this status
+    Refused(EventStatus),
+    Invalid(String),
+}
+
+// Responds to a change to an event, emailing attendees in the background when it affects them
+fn saved_event_response(
+    pool: &Data<AppState>,
+    saved: Result<EventSave, sqlx::Error>,
+    refused: impl FnOnce(EventStatus) -> String,
+) -> HttpResponse {
+    let (event, changes) = match saved {
+        Ok(EventSave::Saved(event, changes)) => (*event, changes),
+        Ok(EventSave::NotFound) => {
+            return HttpResponse::NotFound().json(json!({
+                "status" : "fail",
+                "error" : "Event not found"
+            }))
+        }
+        Ok(EventSave::Invalid(error)) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : error
+            }))
+        }
+        Ok(EventSave::Refused(status)) => {
+            return HttpResponse::Conflict().json(json!({
+                "status" : "fail",
+                "code" : "invalid_event_status",
+                "error" : refused(status),
+                "event_status" : status
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
+            }))
+        }
+    };
+
+    let notify = changes.iter().any(FieldChange::concerns_attendees);
+    if notify {
+        let pool = pool.clone();
+        let event = event.clone();
+        let changes = changes.clone();
+        actix_rt::spawn(async move {
+            if let Err(err) =
+                notify_attendees(&pool.db, pool.mailer.as_ref(), &event, &changes).await
+            {
+                log::warn!("Failed to notify attendees of {}: {}", event.event_id, err);
+            }
+        });
+    }
+
+    HttpResponse::Ok().json(json!({
+        "status" : "success",
+        "data" : event,
+        "changed" : changes.iter().map(|change| change.field).collect::<Vec<_>>(),
+        "attendees_notified" : notify
+    }))
+}
+
+// Validated values from an EventUpdate; None leaves a field as it is
+struct EventEdit<'a> {
+    event_name: Option<&'a str>,
+    schedule: ScheduleInput<'a>,
+    event_location: Option<&'a str>,
+    event_description: Option<Option<&'a str>>,
+    category: Option<Option<&'a str>>,
+}
+
+// Saves an edit and its audit entries, returning the event and what changed.
+// None if the event doesn't exist.
+async fn apply_event_update(
+    db: &Pool<Postgres>,
+    event_id: Uuid,
+    changed_by: Uuid,
+    edit: EventEdit<'_>,
+) -> Result<EventSave, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let Some(before) = sqlx::query_as!(
+        Event,
+        "SELECT * FROM events WHERE event_id = $1 FOR UPDATE",
+        event_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(EventSave::NotFound);
+    };
+    if !before.status().accepts_edits() {
+        return Ok(EventSave::Refused(before.status()));
+    }
+    let schedule = match update_schedule(before.schedule(), edit.schedule) {
+        Ok(schedule) => schedule,
+        Err(err) => return Ok(EventSave::Invalid(err.to_string())),
+    };
+    if schedule != before.schedule() && schedule.ends_at <= Utc::now() {
+        return Ok(EventSave::Invalid(
+            "Event can't be moved to end in the past".to_string(),
+        ));
+    }
+
+    let after = Event {
+        event_name: edit
+            .event_name
+            .map_or_else(|| before.event_name.clone(), str::to_string),
+        event_date: schedule.event_date(),
+        starts_at: schedule.starts_at,
+        ends_at: schedule.ends_at,
+        doors_open_at: schedule.doors_open_at,
+        timezone: schedule.timezone.name().to_string(),
+        event_location: edit
+            .event_location
+            .map(str::to_string)
+            .or_else(|| before.event_location.clone()),
+        event_description: match edit.event_description {
+            Some(description) => description.map(str::to_string),
+            None => before.event_description.clone(),
+        },
+        category: match edit.category {
+            Some(category) => category.map(str::to_string),
+            None => before.category.clone(),
+        },
+        ..before.clone()
+    };
+    let changes = diff_events(&before, &after);
+    if changes.is_empty() {
+        return Ok(EventSave::Saved(Box::new(before), changes));
+    }
+
+    let event = sqlx::query_as!(
+        Event,
+        "UPDATE events SET event_name = $2, event_date = $3, event_location = $4,
+            event_description = $5, starts_at = $6, ends_at = $7, doors_open_at = $8,
+            timezone = $9, category = $10
+        WHERE event_id = $1
+        RETURNING *",
+        event_id,
+        after.event_name,
+        after.event_date,
+        after.event_location,
+        after.event_description,
+        after.starts_at,
+        after.ends_at,
+        after.doors_open_at,
+        after.timezone,
+        after.category
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    // Tickets keep their own copy of the event name
+    if event.event_name != before.event_name {
+        sqlx::query!(
+            "UPDATE tickets SET event_name = $2 WHERE event_id = $1",
+            event_id,
+            event.event_name
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    record_event_changes(&mut tx, event_id, Some(changed_by), &changes).await?;
+    tx.commit().await?;
+    Ok(EventSave::Saved(Box::new(event), changes))
+}
+
+#[post("/api/v1/events/{event_id}/publish")]
+async fn publish_event(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    change_event_status(
+        &pool,
+        &jwt_guard.user,
+        event_id.into_inner(),
+        EventStatus::Published,
+    )
+    .await
+}
+
+#[post("/api/v1/events/{event_id}/pause-sales")]
+async fn pause_event_sales(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    change_event_status(
+        &pool,
+        &jwt_guard.user,
+        event_id.into_inner(),
+        EventStatus::SalesPaused,
+    )
+    .await
+}
+
+#[post("/api/v1/events/{event_id}/postpone")]
+async fn postpone_event(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    change_event_status(
+        &pool,
+        &jwt_guard.user,
+        event_id.into_inner(),
+        EventStatus::Postponed,
+    )
+    .await
+}
+
+#[post("/api/v1/events/{event_id}/cancel")]
+async fn cancel_event(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    change_event_status(
+        &pool,
+        &jwt_guard.user,
+        event_id.into_inner(),
+        EventStatus::Cancelled,
+    )
+    .await
+}
+
+async fn change_event_status(
+    pool: &Data<AppState>,
+    user: &User,
+    event_id: Uuid,
+    next: EventStatus,
+) -> HttpResponse {
+    if let Err(response) = require_event_role(&pool.db, user, event_id, &[EventRole::Owner]).await {
+        return response;
+    }
+    let saved = save_event_status(&pool.db, event_id, user.user_id, next).await;
+    saved_event_response(pool, saved, |status| {
+        format!(
+            "A {} event can't be made {}",
+            status.as_str(),
+            next.as_str()
+        )
+    })
+}
+
+async fn save_event_status(
+    db: &Pool<Postgres>,
+    event_id: Uuid,
+    changed_by: Uuid,
+    next: EventStatus,
+) -> Result<EventSave, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let Some(before) = sqlx::query_as!(
+        Event,
+        "SELECT * FROM events WHERE event_id = $1 FOR UPDATE",
+        event_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(EventSave::NotFound);
+    };
+    let current = before.status();
+    if !current.can_become(next) {
+        return Ok(EventSave::Refused(current));
+    }
+
+    let event = sqlx::query_as!(
+        Event,
+        "UPDATE events SET event_status = $2 WHERE event_id = $1 RETURNING *",
+        event_id,
+        next.as_str()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let changes = vec![FieldChange::status(current, next)];
+    record_event_changes(&mut tx, event_id, Some(changed_by), &changes).await?;
+    tx.commit().await?;
+    Ok(EventSave::Saved(Box::new(event), changes))
+}
+
+// Audit trail of edits to an event, newest first
+#[get("/api/v1/events/{event_id}/changes")]
+async fn get_event_changes(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
     let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+    match sqlx::query_as!(
+        EventChange,
+        "SELECT * FROM event_changes WHERE event_id = $1 ORDER BY changed_at DESC, field",
+        event_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(changes) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : changes
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[delete("/api/v1/events/{event_id}")]
+async fn delete_event(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    remove_event(&pool, &jwt_guard.user, event_id.into_inner()).await
+}
+
+pub async fn remove_event(pool: &AppState, user: &User, event_id: Uuid) -> HttpResponse {
+    if let Err(response) = require_event_role(&pool.db, user, event_id, &[EventRole::Owner]).await {
+        return response;
+    }
     match sqlx::query!("DELETE FROM events where event_id = $1", event_id)
         .execute(&pool.db)
         .await
@@ -166,24 +767,157 @@ async fn delete_event(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Respon
     }
 }
 
-pub async fn check_and_update_events(pool: Data<AppState>) {
-    println!("Running scheduled task...");
-    let current_date = Utc::now().naive_utc().date();
+#[get("/api/v1/events/{event_id}/members")]
+async fn get_event_members(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+    match sqlx::query_as!(
+        EventMember,
+        "SELECT * FROM event_members WHERE event_id = $1",
+        event_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(members) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : members
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[post("/api/v1/events/{event_id}/members")]
+async fn add_event_member(
+    event_id: Path<Uuid>,
+    member: Json<NewEventMember>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) =
+        require_event_role(&pool.db, &jwt_guard.user, event_id, &[EventRole::Owner]).await
+    {
+        return response;
+    }
+    // Ownership can't be handed out through this endpoint
+    let role = match EventRole::parse(&member.role) {
+        Some(role @ (EventRole::CoOrganizer | EventRole::Scanner)) => role,
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "Role must be co_organizer or scanner"
+            }))
+        }
+    };
+    match sqlx::query_as!(
+        EventMember,
+        "INSERT INTO event_members (event_id, user_id, role)
+        SELECT $1, user_id, $2 FROM users WHERE email = $3
+        ON CONFLICT (event_id, user_id) DO UPDATE SET role = EXCLUDED.role
+        WHERE event_members.role <> 'owner'
+        RETURNING *",
+        event_id,
+        role.as_str(),
+        member.email
+    )
+    .fetch_optional(&pool.db)
+    .await
+    {
+        Ok(Some(member)) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : member
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status" : "fail",
+            "error" : "No user with that email, or the user owns this event"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
 
-    // Execute SQL query to mark events as true where event_date <= current_date
-    let query_res = sqlx::query!(
-        "UPDATE events SET event_status = TRUE WHERE event_date <= $1",
-        current_date
+#[delete("/api/v1/events/{event_id}/members/{user_id}")]
+async fn remove_event_member(
+    path: Path<(Uuid, Uuid)>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let (event_id, user_id) = path.into_inner();
+    if let Err(response) =
+        require_event_role(&pool.db, &jwt_guard.user, event_id, &[EventRole::Owner]).await
+    {
+        return response;
+    }
+    match sqlx::query!(
+        "DELETE FROM event_members WHERE event_id = $1 AND user_id = $2 AND role <> 'owner'",
+        event_id,
+        user_id
     )
     .execute(&pool.db)
-    .await;
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => HttpResponse::NotFound().json(json!({
+            "status" : "fail",
+            "error" : "Member not found, or the member is the event owner"
+        })),
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
 
-    match query_res {
-        Ok(_) => {
-            println!("Events marked");
-        }
-        Err(err) => {
-            eprintln!("Failed to mark events: {:?}", err);
-        }
+pub async fn check_and_update_events(pool: Data<AppState>) {
+    match complete_past_events(&pool.db, Utc::now()).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("Marked {} ended events completed", count),
+        Err(err) => log::error!("Failed to mark ended events completed: {}", err),
+    }
+}
+
+// Completes events that have ended, recording each in its audit trail
+async fn complete_past_events(
+    db: &Pool<Postgres>,
+    now: DateTime<Utc>,
+) -> Result<usize, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let completed = sqlx::query!(
+        "UPDATE events e SET event_status = $1
+        FROM (
+            SELECT event_id, event_status FROM events
+            WHERE ends_at <= $2 AND event_status = ANY($3)
+            FOR UPDATE
+        ) before
+        WHERE e.event_id = before.event_id
+        RETURNING e.event_id, before.event_status",
+        EventStatus::Completed.as_str(),
+        now,
+        &EventStatus::able_to_become(EventStatus::Completed)[..] as &[&str]
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for event in &completed {
+        let before = EventStatus::parse(&event.event_status).unwrap_or(EventStatus::Published);
+        let changes = [FieldChange::status(before, EventStatus::Completed)];
+        record_event_changes(&mut tx, event.event_id, None, &changes).await?;
     }
+    tx.commit().await?;
+    Ok(completed.len())
 }
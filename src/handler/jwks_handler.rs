@@ -0,0 +1,10 @@
+use crate::models::AppState;
+use actix_web::{get, web::Data, HttpResponse, Responder};
+
+// Public keys for verifying our JWTs without holding the signing key
+#[get("/.well-known/jwks.json")]
+async fn jwks(pool: Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(pool.keyring.jwks())
+}
@@ -0,0 +1,51 @@
+use crate::{
+    handler::{
+        booking_handler::remove_booking, event_handlers::remove_event,
+        ticket_handlers::remove_ticket,
+    },
+    jwt_auth,
+    models::AppState,
+};
+use actix_web::{
+    delete,
+    web::{Data, Path},
+    HttpResponse,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+// DELETE /delete/{id} used to be registered for events, tickets and bookings at
+// once, so only the first registration was ever reachable. Until the legacy
+// routes are dropped, look up which resource the id belongs to and delete that.
+#[delete("/delete/{id}")]
+async fn delete_by_id(
+    id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let kind = sqlx::query_scalar!(
+        "SELECT CASE
+            WHEN EXISTS (SELECT 1 FROM events WHERE event_id = $1) THEN 'event'
+            WHEN EXISTS (SELECT 1 FROM tickets WHERE ticket_id = $1) THEN 'ticket'
+            WHEN EXISTS (SELECT 1 FROM bookings WHERE booking_id = $1) THEN 'booking'
+        END",
+        id
+    )
+    .fetch_one(&pool.db)
+    .await;
+
+    match kind.as_ref().map(|kind| kind.as_deref()) {
+        Ok(Some("event")) => remove_event(&pool, &jwt_guard.user, id).await,
+        Ok(Some("ticket")) => remove_ticket(&pool, &jwt_guard.user, id).await,
+        Ok(Some("booking")) => remove_booking(&pool, &jwt_guard.user, id).await,
+        Ok(_) => HttpResponse::NotFound().json(json!({
+            "status" : "fail",
+            "error" : "Not found"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
@@ -0,0 +1,306 @@
+use crate::handler::user_handlers::{
+    client_ip, record_login_failure, start_login_session, too_many_attempts,
+};
+use crate::jwt_auth;
+use crate::login_throttle::AttemptKey;
+use crate::models::{AppState, MfaChallenge, MfaPolicy, TotpCode};
+use crate::roles::{Admin, RequireRole, Role};
+use crate::totp::{
+    challenge_user, generate_recovery_codes, generate_secret, is_enrolled, provisioning_uri,
+    redeem_challenge, redeem_recovery_code, role_requires_mfa, verify_code,
+};
+use actix_web::{
+    post, put,
+    web::{Data, Json, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use std::time::Instant;
+
+fn server_error(err: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError().json(json!({
+        "status" : "fail",
+        "error" : err.to_string()
+    }))
+}
+
+// Starts enrollment; nothing changes for login until the confirm step
+#[post("/api/v1/auth/2fa/enroll")]
+async fn enroll_totp(pool: Data<AppState>, jwt_guard: jwt_auth::JwtMiddleware) -> impl Responder {
+    let user = jwt_guard.user;
+    match is_enrolled(&pool.db, user.user_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "Two-factor authentication is already enabled"
+            }))
+        }
+        Err(err) => return server_error(err),
+    }
+
+    let secret = generate_secret();
+    match sqlx::query!(
+        "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE
+        SET secret = EXCLUDED.secret, created_at = NOW(), last_used_step = NULL",
+        user.user_id,
+        secret
+    )
+    .execute(&pool.db)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : {
+                "secret" : secret,
+                "otpauth_uri" : provisioning_uri(&secret, &user.email)
+            }
+        })),
+        Err(err) => server_error(err),
+    }
+}
+
+#[post("/api/v1/auth/2fa/confirm")]
+async fn confirm_totp(
+    data: Json<TotpCode>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let user_id = jwt_guard.user.user_id;
+    let mut tx = match pool.db.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return server_error(err),
+    };
+
+    match sqlx::query_scalar!(
+        "SELECT enabled_at IS NULL FROM user_totp WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(Some(true))) => {}
+        Ok(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "No two-factor enrollment is waiting to be confirmed"
+            }))
+        }
+        Err(err) => return server_error(err),
+    }
+
+    match verify_code(&mut tx, user_id, &data.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "Invalid code"
+            }))
+        }
+        Err(err) => return server_error(err),
+    }
+
+    if let Err(err) = sqlx::query!(
+        "UPDATE user_totp SET enabled_at = NOW() WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    {
+        return server_error(err);
+    }
+    let recovery_codes = match generate_recovery_codes(&mut tx, user_id).await {
+        Ok(codes) => codes,
+        Err(err) => return server_error(err),
+    };
+    if let Err(err) = tx.commit().await {
+        return server_error(err);
+    }
+
+    HttpResponse::Ok().json(json!({
+        "status" : "success",
+        "data" : { "recovery_codes" : recovery_codes }
+    }))
+}
+
+#[post("/api/v1/auth/2fa/disable")]
+async fn disable_totp(
+    data: Json<TotpCode>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let user = jwt_guard.user;
+    match role_requires_mfa(&pool.db, &user.role).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Forbidden().json(json!({
+                "status" : "fail",
+                "error" : "Two-factor authentication is required for your role"
+            }))
+        }
+        Err(err) => return server_error(err),
+    }
+
+    let mut tx = match pool.db.begin().await {
+        Ok(tx) => tx,
+        Err(err) => return server_error(err),
+    };
+    let passed = match verify_code(&mut tx, user.user_id, &data.code).await {
+        Ok(true) => true,
+        Ok(false) => match redeem_recovery_code(&mut tx, user.user_id, &data.code).await {
+            Ok(redeemed) => redeemed,
+            Err(err) => return server_error(err),
+        },
+        Err(err) => return server_error(err),
+    };
+    if !passed {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Invalid code"
+        }));
+    }
+
+    if let Err(err) = sqlx::query!(
+        "WITH codes AS (DELETE FROM totp_recovery_codes WHERE user_id = $1)
+        DELETE FROM user_totp WHERE user_id = $1",
+        user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    {
+        return server_error(err);
+    }
+    match tx.commit().await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : "Two-factor authentication disabled"
+        })),
+        Err(err) => server_error(err),
+    }
+}
+
+// Second login step for enrolled users
+#[post("/api/v1/auth/2fa/verify")]
+async fn verify_totp_login(
+    req: HttpRequest,
+    data: Json<MfaChallenge>,
+    pool: Data<AppState>,
+) -> impl Responder {
+    let invalid = || {
+        HttpResponse::Unauthorized().json(json!({
+            "status" : "fail",
+            "error" : "Invalid code or expired login attempt"
+        }))
+    };
+    let user = match challenge_user(&pool.db, &data.challenge_token).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return invalid(),
+        Err(err) => return server_error(err),
+    };
+
+    // Wrong codes count against the account like wrong passwords, so logging
+    // in again for a fresh challenge doesn't buy more guesses
+    let keys = [AttemptKey::Account(&user.email)];
+    if let Some(retry_after) = pool.login_throttle.retry_after(&keys, Instant::now()) {
+        return too_many_attempts(retry_after);
+    }
+    match redeem_challenge(&pool.db, &data.challenge_token, &data.code).await {
+        Ok(Some(_)) => start_login_session(&req, &pool, user).await,
+        Ok(None) => {
+            record_login_failure(&pool, &keys, Some(user.user_id), &client_ip(&req)).await;
+            invalid()
+        }
+        Err(err) => server_error(err),
+    }
+}
+
+#[put("/api/v1/roles/{role}/mfa")]
+async fn set_role_mfa_policy(
+    role: Path<String>,
+    data: Json<MfaPolicy>,
+    pool: Data<AppState>,
+    _admin: RequireRole<Admin>,
+) -> impl Responder {
+    let role = match Role::parse(&role) {
+        Some(role) => role,
+        None => {
+            return HttpResponse::BadRequest().json(json!({
+                "status" : "fail",
+                "error" : "Unknown role"
+            }))
+        }
+    };
+
+    match sqlx::query!(
+        "INSERT INTO role_mfa_policies (role, required) VALUES ($1, $2)
+        ON CONFLICT (role) DO UPDATE SET required = EXCLUDED.required, updated_at = NOW()",
+        role.as_str(),
+        data.required
+    )
+    .execute(&pool.db)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : { "role" : role.as_str(), "mfa_required" : data.required }
+        })),
+        Err(err) => server_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::user_handlers::get_user;
+    use crate::test_support::{create_user, delete_users, test_state, PASSWORD};
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn wrong_codes_lock_the_account_across_challenges() {
+        let state = test_state().await;
+        let user = create_user(&state, Role::Attendee).await;
+        sqlx::query!(
+            "INSERT INTO user_totp (user_id, secret, enabled_at) VALUES ($1, $2, NOW())",
+            user.user_id,
+            generate_secret()
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(get_user)
+                .service(verify_totp_login),
+        )
+        .await;
+        let login = || {
+            test::TestRequest::post()
+                .uri("/api/v1/auth/login")
+                .set_json(json!({ "email": user.email, "password": PASSWORD }))
+                .to_request()
+        };
+
+        // One wrong code per challenge, each after a correct password
+        for _ in 0..5 {
+            let response = test::call_service(&app, login()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert_eq!(body["status"], "mfa_required");
+            let verify = test::TestRequest::post()
+                .uri("/api/v1/auth/2fa/verify")
+                .set_json(json!({ "challenge_token": body["challenge_token"], "code": "wrong" }))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, verify).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        let response = test::call_service(&app, login()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        delete_users(&state, &[&user]).await;
+    }
+}
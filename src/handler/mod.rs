@@ -1,4 +1,12 @@
 pub mod user_handlers;
 pub mod ticket_handlers;
 pub mod event_handlers;
-pub mod booking_handler;
\ No newline at end of file
+pub mod booking_handler;
+pub mod legacy_handler;
+pub mod session_handlers;
+pub mod mfa_handlers;
+pub mod jwks_handler;
+pub mod oidc_handlers;
+pub mod api_key_handlers;
+pub mod scan_handlers;
+pub mod admission_handlers;
\ No newline at end of file
@@ -0,0 +1,481 @@
+use crate::api_key::{ApiPermission, Caller};
+use crate::check_in::{
+    entry_policy, scan_window, sync_offline_scans, Scanner, MAX_SCANS_PER_UPLOAD,
+};
+use crate::check_in_feed::feed_stream;
+use crate::handler::booking_handler::booking_holder_and_event;
+use crate::jwt_auth;
+use crate::models::{AppState, CheckIn, EntryPolicy, FeedQuery, ScanUpload};
+use crate::roles::{require_event_role, DOOR_STAFF, ORGANIZERS};
+use crate::ticket_credential::{issue_scan_manifest, ManifestEntry};
+use actix_web::{
+    get, post, put,
+    web::{Data, Json, Path, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+// How long a scanner may work from one manifest before fetching a new one
+const MANIFEST_HOURS: i64 = 12;
+
+// Signed list of the event's valid admissions, for scanners that lose connectivity
+#[get("/api/v1/events/{event_id}/scan-manifest")]
+async fn get_scan_manifest(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = caller
+        .require(&pool.db, event_id, DOOR_STAFF, ApiPermission::Verify)
+        .await
+    {
+        return response;
+    }
+
+    let admissions = match sqlx::query!(
+        "SELECT a.admission_id, a.booking_id, b.user_id AS \"user_id!\", a.holder_name, a.verified
+        FROM admissions a
+        JOIN bookings b ON b.booking_id = a.booking_id
+        JOIN tickets t ON t.ticket_id = b.ticket_id
+        WHERE t.event_id = $1 AND b.user_id IS NOT NULL
+        ORDER BY a.booking_id, a.seq",
+        event_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(admissions) => admissions,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": err.to_string()
+            }))
+        }
+    };
+
+    let entries: Vec<ManifestEntry> = admissions
+        .into_iter()
+        .map(|admission| ManifestEntry {
+            aid: admission.admission_id,
+            bid: admission.booking_id,
+            sub: admission.user_id,
+            name: admission.holder_name,
+            used: admission.verified,
+        })
+        .collect();
+    let count = entries.len();
+    let manifest_id = Uuid::new_v4();
+    let issued_at = chrono::Utc::now();
+    let expires_at = issued_at + chrono::Duration::hours(MANIFEST_HOURS);
+    if let Err(err) = sqlx::query!(
+        "INSERT INTO scan_manifests (manifest_id, event_id, issued_at, expires_at)
+        VALUES ($1, $2, $3, $4)",
+        manifest_id,
+        event_id,
+        issued_at.naive_utc(),
+        expires_at.naive_utc()
+    )
+    .execute(&pool.db)
+    .await
+    {
+        return HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        }));
+    }
+    match issue_scan_manifest(
+        &pool.keyring,
+        manifest_id,
+        event_id,
+        entries,
+        issued_at,
+        expires_at,
+    ) {
+        Ok(manifest) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "manifest_id": manifest_id,
+            "manifest": manifest,
+            "admissions": count,
+            "expires_at": expires_at
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": format!("Failed to sign scan manifest: {}", err)
+        })),
+    }
+}
+
+// Uploads scans a device recorded while offline
+#[post("/api/v1/events/{event_id}/check-ins/sync")]
+async fn sync_scans(
+    event_id: Path<Uuid>,
+    upload: Json<ScanUpload>,
+    pool: Data<AppState>,
+    caller: Caller,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = caller
+        .require(&pool.db, event_id, DOOR_STAFF, ApiPermission::Verify)
+        .await
+    {
+        return response;
+    }
+
+    let device_id = upload.device_id.trim();
+    if device_id.is_empty() || device_id.chars().count() > 100 {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Device id must be 1 to 100 characters"
+        }));
+    }
+    if upload
+        .gate
+        .as_ref()
+        .is_some_and(|gate| gate.chars().count() > 100)
+    {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Gate must be at most 100 characters"
+        }));
+    }
+    if upload.scans.len() > MAX_SCANS_PER_UPLOAD {
+        return HttpResponse::PayloadTooLarge().json(json!({
+            "status": "fail",
+            "error": format!("Upload at most {} scans at a time", MAX_SCANS_PER_UPLOAD)
+        }));
+    }
+
+    let window = match scan_window(&pool.db, event_id, upload.manifest_id).await {
+        Ok(Some(window)) => window,
+        Ok(None) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "error": "Unknown scan manifest for this event"
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": format!("Failed to sync scans: {}", err)
+            }))
+        }
+    };
+
+    let scanner = Scanner::new(&caller, upload.gate.as_deref());
+    match sync_offline_scans(
+        &pool.db,
+        &pool.keyring,
+        event_id,
+        &scanner,
+        device_id,
+        &window,
+        &upload.scans,
+    )
+    .await
+    {
+        Ok((results, conflicts)) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "results": results,
+            "conflicts": conflicts
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": format!("Failed to sync scans: {}", err)
+        })),
+    }
+}
+
+// Every scan of a booking's admissions, including the ones turned away
+#[get("/api/v1/bookings/{booking_id}/check-ins")]
+async fn get_check_ins(
+    booking_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let booking_id = booking_id.into_inner();
+    let event_id = match booking_holder_and_event(&pool, booking_id).await {
+        Ok((_, event_id)) => event_id,
+        Err(response) => return response,
+    };
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+
+    match sqlx::query_as!(
+        CheckIn,
+        "SELECT * FROM check_ins WHERE booking_id = $1 ORDER BY scanned_at, recorded_at",
+        booking_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(check_ins) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": check_ins
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        })),
+    }
+}
+
+// Live arrivals for the event's organizers, as server-sent events: a snapshot
+// of the counts and recent scans, then each scan and the updated counts
+#[get("/api/v1/events/{event_id}/check-ins/live")]
+async fn get_check_in_feed(
+    req: HttpRequest,
+    event_id: Path<Uuid>,
+    query: Query<FeedQuery>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+
+    // Browsers send the id of the last event they saw when they reconnect
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(value) => match value.to_str().ok().and_then(|id| id.trim().parse().ok()) {
+            Some(id) => Some(id),
+            None => {
+                return HttpResponse::BadRequest().json(json!({
+                    "status": "fail",
+                    "error": "Last-Event-ID must be the id of a check-in event"
+                }))
+            }
+        },
+        None => query.last_event_id,
+    };
+
+    let jwt_auth::JwtMiddleware { user, session_id } = jwt_guard;
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(feed_stream(
+            pool.db.clone(),
+            &pool.check_in_feed,
+            user,
+            session_id,
+            event_id,
+            last_event_id,
+        ))
+}
+
+#[get("/api/v1/events/{event_id}/entry-policy")]
+async fn get_entry_policy(
+    event_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, DOOR_STAFF).await
+    {
+        return response;
+    }
+
+    match entry_policy(&pool.db, event_id).await {
+        Ok(policy) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": policy
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        })),
+    }
+}
+
+// Whether attendees may leave and come back, and how many times
+#[put("/api/v1/events/{event_id}/entry-policy")]
+async fn set_entry_policy(
+    event_id: Path<Uuid>,
+    data: Json<EntryPolicy>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let event_id = event_id.into_inner();
+    if let Err(response) = require_event_role(&pool.db, &jwt_guard.user, event_id, ORGANIZERS).await
+    {
+        return response;
+    }
+    if data.max_entries.is_some_and(|max| max <= 0) {
+        return HttpResponse::BadRequest().json(json!({
+            "status": "fail",
+            "error": "Max entries must be a positive number"
+        }));
+    }
+
+    match sqlx::query!(
+        "INSERT INTO event_entry_policies (event_id, reentry_allowed, max_entries)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (event_id) DO UPDATE SET
+            reentry_allowed = EXCLUDED.reentry_allowed,
+            max_entries = EXCLUDED.max_entries,
+            updated_at = NOW()",
+        event_id,
+        data.reentry_allowed,
+        data.max_entries
+    )
+    .execute(&pool.db)
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "data": data.into_inner()
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::Role;
+    use crate::test_support::{
+        access_token, create_event, create_ticket, create_user, delete_users, test_state,
+    };
+    use crate::ticket_credential::{issue_ticket_credential, TicketCredential};
+    use actix_web::{http::StatusCode, test, App};
+    use chrono::{Duration, Utc};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn rejects_scans_dated_outside_their_window() {
+        let state = test_state().await;
+        let organizer = create_user(&state, Role::Organizer).await;
+        let attendee = create_user(&state, Role::Attendee).await;
+        let event_id = create_event(&state, &organizer).await;
+        let ticket_id = create_ticket(&state, event_id, 10).await;
+        let now = Utc::now();
+        sqlx::query!(
+            "UPDATE events SET starts_at = $2, ends_at = $3 WHERE event_id = $1",
+            event_id,
+            now - Duration::hours(2),
+            now + Duration::hours(2)
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let (booking_id, admission_id) = (Uuid::new_v4(), Uuid::new_v4());
+        sqlx::query!(
+            "WITH booking AS (
+                INSERT INTO bookings (booking_id, user_id, ticket_id, quantity, total_price)
+                VALUES ($1, $2, $3, 1, 100)
+            )
+            INSERT INTO admissions (admission_id, booking_id, seq) VALUES ($4, $1, 1)",
+            booking_id,
+            attendee.user_id,
+            ticket_id,
+            admission_id
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let credential = issue_ticket_credential(
+            &state.keyring,
+            &TicketCredential {
+                booking_id,
+                admission_id,
+                event_id,
+                holder: attendee.user_id,
+                ticket_id,
+                seat: None,
+            },
+            now + Duration::hours(2),
+        )
+        .unwrap();
+        let token = access_token(&state, &organizer).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(get_scan_manifest)
+                .service(sync_scans),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/api/v1/events/{}/scan-manifest", event_id))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        let manifest_id: Uuid = serde_json::from_value(body["manifest_id"].clone()).unwrap();
+        // As if the device had fetched it a while before going offline
+        sqlx::query!(
+            "UPDATE scan_manifests SET issued_at = $2 WHERE manifest_id = $1",
+            manifest_id,
+            (now - Duration::hours(5)).naive_utc()
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let sync = |manifest_id: Uuid| {
+            let scans: Vec<Value> = [
+                Duration::hours(1),
+                Duration::hours(-1),
+                Duration::hours(-3),
+                Duration::hours(-6),
+                Duration::seconds(-30),
+            ]
+            .into_iter()
+            .map(|offset| json!({ "credential": credential, "scanned_at": now + offset }))
+            .collect();
+            test::TestRequest::post()
+                .uri(&format!("/api/v1/events/{}/check-ins/sync", event_id))
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .set_json(json!({
+                    "device_id": "gate-1",
+                    "manifest_id": manifest_id,
+                    "scans": scans
+                }))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, sync(Uuid::new_v4())).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = test::call_service(&app, sync(manifest_id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(response).await;
+        let mut results: Vec<(u64, String, Value)> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                (
+                    result["index"].as_u64().unwrap(),
+                    result["status"].as_str().unwrap().to_string(),
+                    result["code"].clone(),
+                )
+            })
+            .collect();
+        results.sort_by_key(|result| result.0);
+        let rejected = |code: &str| ("rejected".to_string(), json!(code));
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|(_, status, code)| (status, code))
+                .collect::<Vec<_>>(),
+            vec![
+                rejected("scanned_in_future"),
+                ("accepted".to_string(), Value::Null),
+                rejected("outside_event_window"),
+                rejected("scanned_before_manifest"),
+                // Within the clock skew allowed, so not in the future
+                ("conflict".to_string(), Value::Null),
+            ]
+        );
+
+        delete_users(&state, &[&organizer, &attendee]).await;
+    }
+}
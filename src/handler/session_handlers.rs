@@ -0,0 +1,100 @@
+use crate::{
+    jwt_auth,
+    models::{AppState, Session},
+    session::{revoke_session, revoke_user_sessions},
+};
+use actix_web::{
+    delete, get,
+    web::{Data, Path},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+// Active sessions are the ones not revoked that still hold a usable refresh token
+#[get("/api/v1/sessions")]
+async fn get_sessions(pool: Data<AppState>, jwt_guard: jwt_auth::JwtMiddleware) -> impl Responder {
+    match sqlx::query_as!(
+        Session,
+        "SELECT s.* FROM sessions s
+        WHERE s.user_id = $1 AND s.revoked_at IS NULL
+        AND EXISTS (
+            SELECT 1 FROM refresh_tokens r
+            WHERE r.family_id = s.session_id AND r.used_at IS NULL
+            AND r.revoked_at IS NULL AND r.expires_at > NOW()
+        )
+        ORDER BY s.last_used_at DESC",
+        jwt_guard.user.user_id
+    )
+    .fetch_all(&pool.db)
+    .await
+    {
+        Ok(sessions) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : sessions,
+            "current_session_id" : jwt_guard.session_id
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+// Registered before /api/v1/sessions/{session_id} so "others" isn't parsed as an id
+#[delete("/api/v1/sessions/others")]
+async fn revoke_other_sessions(
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    match revoke_user_sessions(&pool.db, jwt_guard.user.user_id, Some(jwt_guard.session_id)).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[delete("/api/v1/sessions/{session_id}")]
+async fn revoke_user_session(
+    session_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let session_id = session_id.into_inner();
+    // Only the owner of a session may revoke it
+    let owner = sqlx::query_scalar!(
+        "SELECT user_id FROM sessions WHERE session_id = $1",
+        session_id
+    )
+    .fetch_optional(&pool.db)
+    .await;
+    match owner {
+        Ok(Some(user_id)) if user_id == jwt_guard.user.user_id => {}
+        Ok(_) => {
+            return HttpResponse::NotFound().json(json!({
+                "status" : "fail",
+                "error" : "Session not found"
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
+            }))
+        }
+    }
+
+    match revoke_session(&pool.db, session_id).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
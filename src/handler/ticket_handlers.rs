@@ -1,9 +1,11 @@
 use crate::{
     jwt_auth,
-    models::{AppState, NewTicket, Ticket},
+    models::{AppState, NewTicket, Ticket, User},
+    money::{Money, DEFAULT_CURRENCY},
+    roles::{require_event_role, ORGANIZERS},
 };
 use actix_web::{
-    delete, get, post,
+    delete, routes,
     web::{Data, Json, Path},
     HttpResponse, Responder,
 };
@@ -11,25 +13,45 @@ use actix_web::{
 use serde_json::json;
 use uuid::Uuid;
 
-#[post("/create_ticket")]
+#[routes]
+#[post("/api/v1/tickets")]
+#[post("/create_ticket")] // legacy
 async fn generate_ticket(
     ticket_data: Json<NewTicket>,
     pool: Data<AppState>,
-    _: jwt_auth::JwtMiddleware,
+    jwt_guard: jwt_auth::JwtMiddleware,
 ) -> impl Responder {
+    if let Err(response) =
+        require_event_role(&pool.db, &jwt_guard.user, ticket_data.event_id, ORGANIZERS).await
+    {
+        return response;
+    }
     // Parse availability from string to integer
     let availability: i32 = ticket_data.availability.parse().unwrap_or(0);
+    let price = match Money::new(
+        ticket_data.price,
+        ticket_data.currency.as_deref().unwrap_or(DEFAULT_CURRENCY),
+    ) {
+        Ok(price) => price,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(json!({
+                "status": "fail",
+                "Error": err,
+            }))
+        }
+    };
 
     let ticket_insert = sqlx::query_as!(
         Ticket,
-        "INSERT INTO tickets (event_id ,ticket_id, event_name ,ticket_type, price, availability)
-                VALUES ($1, $2, $3, $4, $5, $6)
+        "INSERT INTO tickets (event_id ,ticket_id, event_name ,ticket_type, price, currency, availability)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
                 RETURNING *",
         ticket_data.event_id,
         Uuid::new_v4(),
         ticket_data.event_name,
         ticket_data.ticket_type,
-        ticket_data.price,
+        price.amount,
+        price.currency,
         availability
     )
     .fetch_one(&pool.db)
@@ -50,7 +72,9 @@ async fn generate_ticket(
     }
 }
 
-#[get("/get_ticket/{event_id}")]
+#[routes]
+#[get("/api/v1/events/{event_id}/tickets")]
+#[get("/get_ticket/{event_id}")] // legacy
 async fn get_ticket(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
     let event_id = event_id.into_inner();
     match sqlx::query_as!(
@@ -75,9 +99,40 @@ async fn get_ticket(event_id: Path<Uuid>, pool: Data<AppState>) -> impl Responde
         )),
     }
 }
-#[delete("/delete/{ticket_id}")]
-async fn delete_ticket(ticket_id: Path<Uuid>, pool: Data<AppState>) -> impl Responder {
-    let ticket_id = ticket_id.into_inner();
+#[delete("/api/v1/tickets/{ticket_id}")]
+async fn delete_ticket(
+    ticket_id: Path<Uuid>,
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    remove_ticket(&pool, &jwt_guard.user, ticket_id.into_inner()).await
+}
+
+pub async fn remove_ticket(pool: &AppState, user: &User, ticket_id: Uuid) -> HttpResponse {
+    let event_id = match sqlx::query_scalar!(
+        "SELECT event_id FROM tickets WHERE ticket_id = $1",
+        ticket_id
+    )
+    .fetch_optional(&pool.db)
+    .await
+    {
+        Ok(Some(Some(event_id))) => event_id,
+        Ok(_) => {
+            return HttpResponse::NotFound().json(json!({
+                "status" : "fail",
+                "error" : "Ticket not found"
+            }))
+        }
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
+            }))
+        }
+    };
+    if let Err(response) = require_event_role(&pool.db, user, event_id, ORGANIZERS).await {
+        return response;
+    }
     match sqlx::query!("DELETE FROM tickets where ticket_id = $1", ticket_id)
         .execute(&pool.db)
         .await
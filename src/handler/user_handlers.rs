@@ -1,34 +1,79 @@
+use crate::email_verification::{send_verification_email, verify_email_token};
 use crate::jwt_auth; // Add missing token module
-use crate::models::{AppState, Login, NewUser, User};
-use crate::token::{generate_jwt_token, verify_jwt_token};
+use crate::keyring::TokenKind;
+use crate::login_throttle::{record_lockout, AttemptKey};
+use crate::models::{
+    AppState, EmailVerification, Login, NewUser, PasswordForgot, PasswordReset, RoleUpdate, User,
+};
+use crate::password_reset::{request_password_reset, reset_password as redeem_password_reset};
+use crate::refresh_token::{
+    issue_refresh_token, revoke_refresh_token, rotate_refresh_token, RefreshError,
+};
+use crate::roles::{Admin, RequireRole, Role};
+use crate::session::create_session;
+use crate::token::generate_jwt_token;
+use crate::totp::{create_challenge, enrollment_pending, is_enrolled};
 use actix_web::{
     cookie::time::Duration,
     cookie::Cookie,
-    delete, get, post,
-    web::{Data, Json},
+    http::header,
+    patch, post, routes,
+    web::{Data, Json, Path},
     HttpRequest, HttpResponse, Responder,
 };
 use bcrypt::{hash, verify, DEFAULT_COST};
 // use chrono::Duration;
 use serde_json::json;
 use std::env;
+use std::sync::OnceLock;
+use std::time::Instant;
 use uuid::Uuid;
 
 // Handler for the add_user route
-#[post("/register")]
-pub async fn add_user(user: Json<NewUser>, pool: Data<AppState>) -> impl Responder {
+#[routes]
+#[post("/api/v1/auth/register")]
+#[post("/register")] // legacy
+pub async fn add_user(
+    req: HttpRequest,
+    user: Json<NewUser>,
+    pool: Data<AppState>,
+) -> impl Responder {
     let user_data = user.into_inner();
+    // Everyone signs up as an attendee. Other roles are granted by an admin.
+    let role = Role::Attendee;
     let password = hash(user_data.password, DEFAULT_COST).expect("Failed to hash password");
 
-    let query_res = sqlx::query_as!(User, "INSERT INTO users (user_id ,username, email, password, first_name, last_name, phone_number) VALUES ($1, $2, $3, $4, $5, $6,$7) RETURNING *", Uuid::new_v4(), user_data.username, user_data.email, password, user_data.first_name, user_data.last_name, user_data.phone_number)
+    let query_res = sqlx::query_as!(User, "INSERT INTO users (user_id ,username, email, password, first_name, last_name, phone_number, role) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *", Uuid::new_v4(), user_data.username, user_data.email, password, user_data.first_name, user_data.last_name, user_data.phone_number, role.as_str())
         .fetch_one(&pool.db)
         .await;
 
     match query_res {
         Ok(data) => {
+            // Registration still succeeds if the mail can't go out; the user can ask for a resend
+            if let Err(err) =
+                send_verification_email(&pool.db, pool.mailer.as_ref(), &pool.keyring, &data).await
+            {
+                log::warn!(
+                    "Failed to send verification email to {}: {}",
+                    data.email,
+                    err
+                );
+            }
+
+            let session_id = match create_session(&pool.db, data.user_id, &req).await {
+                Ok(session_id) => session_id,
+                Err(err) => {
+                    return HttpResponse::InternalServerError()
+                        .json(json!({ "error": err.to_string() }));
+                }
+            };
+
             let access_token = match generate_jwt_token(
+                &pool.keyring,
+                TokenKind::Access,
                 data.user_id,
-                &env::var("ACCESS_SECRET_KEY").unwrap(),
+                session_id,
+                Some(&data.role),
                 1,
             ) {
                 Ok(token) => token,
@@ -39,7 +84,8 @@ pub async fn add_user(user: Json<NewUser>, pool: Data<AppState>) -> impl Respond
             };
 
             let refresh_token =
-                match generate_jwt_token(data.user_id, &env::var("REFRESH_SECRET_KEY").unwrap(), 1)
+                match issue_refresh_token(&pool.db, &pool.keyring, data.user_id, session_id, 1)
+                    .await
                 {
                     Ok(token) => token,
                     Err(err) => {
@@ -90,16 +136,27 @@ pub async fn add_user(user: Json<NewUser>, pool: Data<AppState>) -> impl Respond
 }
 
 // Handler for the get_user route
-#[post("/login")]
-async fn get_user(data: Json<Login>, pool: Data<AppState>) -> impl Responder {
+#[routes]
+#[post("/api/v1/auth/login")]
+#[post("/login")] // legacy
+async fn get_user(req: HttpRequest, data: Json<Login>, pool: Data<AppState>) -> impl Responder {
     let login_data = data.into_inner();
+    let ip_address = client_ip(&req);
+    let keys = [
+        AttemptKey::Account(&login_data.email),
+        AttemptKey::Ip(&ip_address),
+    ];
+    if let Some(retry_after) = pool.login_throttle.retry_after(&keys, Instant::now()) {
+        return too_many_attempts(retry_after);
+    }
+
     let user = match sqlx::query_as!(
         User,
         "SELECT * FROM users
         WHERE email = $1 ",
         login_data.email
     )
-    .fetch_one(&pool.db)
+    .fetch_optional(&pool.db)
     .await
     {
         Ok(user) => user,
@@ -109,73 +166,244 @@ async fn get_user(data: Json<Login>, pool: Data<AppState>) -> impl Responder {
         }
     };
 
-    // Verify the password
-    let password_match = match verify(login_data.password, &user.password) {
-        Ok(matched) => matched,
-        Err(_) => {
-            return HttpResponse::BadRequest()
-                .json(json!({ "error": "Error occurred during verification" }))
+    // Unknown emails and passwordless accounts still go through bcrypt so
+    // the response time doesn't give away which accounts exist
+    let password_hash = match user.as_ref().and_then(|user| user.password.as_deref()) {
+        Some(password) => password,
+        None => dummy_password_hash(),
+    };
+    let password_match = verify(&login_data.password, password_hash).unwrap_or(false);
+
+    let user = match user {
+        Some(user) if password_match && user.password.is_some() => user,
+        user => {
+            let user_id = user.map(|user| user.user_id);
+            record_login_failure(&pool, &keys, user_id, &ip_address).await;
+            return HttpResponse::Unauthorized().json(json!({
+                "status": "fail",
+                "error": "Invalid credentials"
+            }));
         }
     };
 
-    if password_match {
-        // Generate JWT token
-        let access_token =
-            match generate_jwt_token(user.user_id, &env::var("ACCESS_SECRET_KEY").unwrap(), 1) {
-                Ok(token) => token,
-                Err(err) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({ "error": err.to_string() }))
-                }
-            };
+    // The account's failures are only cleared once the whole login has
+    // passed, so a correct password can't reset the count for the 2FA step
+    finish_login(&req, &pool, user).await
+}
 
-        // Generate refresh token
-        let refresh_token =
-            match generate_jwt_token(user.user_id, &env::var("REFRESH_SECRET_KEY").unwrap(), 7) {
-                Ok(token) => token,
+/// The address login attempts are throttled by. This is the peer the
+/// request came from, as forwarding headers are up to the client.
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub fn too_many_attempts(retry_after: std::time::Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        ))
+        .json(json!({
+            "status": "fail",
+            "error": "Too many failed login attempts, please try again later"
+        }))
+}
+
+/// Counts a failed login step against each of `keys`, logging and auditing
+/// any lockout it causes.
+pub async fn record_login_failure(
+    pool: &AppState,
+    keys: &[AttemptKey<'_>],
+    user_id: Option<Uuid>,
+    ip_address: &str,
+) {
+    let now = Instant::now();
+    for key in keys {
+        let Some(lockout) = pool.login_throttle.record_failure(*key, now) else {
+            continue;
+        };
+        log::warn!(
+            "Locked out {:?} for {}s after {} failed logins",
+            key,
+            lockout.duration.as_secs(),
+            lockout.failures
+        );
+        if let Err(err) = record_lockout(&pool.db, *key, user_id, ip_address, lockout).await {
+            log::warn!("Failed to record lockout: {}", err);
+        }
+    }
+}
+
+// Hash of a random password, checked against when the email is unknown
+fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash(Uuid::new_v4().to_string(), DEFAULT_COST).unwrap())
+}
+
+/// Completes a login once the user's first factor has been checked, asking
+/// for a TOTP code if they have enrolled in 2FA.
+pub async fn finish_login(req: &HttpRequest, pool: &AppState, user: User) -> HttpResponse {
+    // Enrolled users finish logging in with a code from their authenticator
+    match is_enrolled(&pool.db, user.user_id).await {
+        Ok(true) => {
+            return match create_challenge(&pool.db, user.user_id).await {
+                Ok(challenge_token) => HttpResponse::Ok().json(json!({
+                    "status": "mfa_required",
+                    "challenge_token": challenge_token
+                })),
                 Err(err) => {
-                    return HttpResponse::InternalServerError()
-                        .json(json!({ "error": err.to_string() }))
+                    HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
                 }
-            };
+            }
+        }
+        Ok(false) => {}
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
+        }
+    }
+    start_login_session(req, pool, user).await
+}
+
+/// Opens a session for a fully authenticated user and returns the login
+/// response with both token cookies.
+pub async fn start_login_session(req: &HttpRequest, pool: &AppState, user: User) -> HttpResponse {
+    pool.login_throttle
+        .record_success(AttemptKey::Account(&user.email));
+
+    // Users whose role requires 2FA can still log in, but are told to enroll
+    let mfa_enrollment_required = match enrollment_pending(&pool.db, &user).await {
+        Ok(pending) => pending,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
+        }
+    };
+
+    // Every login gets its own session
+    let session_id = match create_session(&pool.db, user.user_id, req).await {
+        Ok(session_id) => session_id,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
+        }
+    };
 
-        // Build access and refresh cookies
-        let refresh_cookie = Cookie::build("refresh_token", refresh_token.token.clone().unwrap())
-            .http_only(true)
-            .secure(true)
-            .same_site(actix_web::cookie::SameSite::None)
-            .max_age(Duration::days(7))
-            .finish();
-        let access_cookie = Cookie::build("access_token", access_token.token.clone().unwrap())
-            .http_only(true)
-            .secure(true)
-            .same_site(actix_web::cookie::SameSite::None)
-            .max_age(Duration::days(1))
-            .finish();
-
-        // Return response with cookies and user data
-        HttpResponse::Ok()
-            .cookie(access_cookie)
-            .cookie(refresh_cookie)
-            .json(json!({
-                "status": "success",
-                "data": {
-                    "user_id" : user.user_id,
-                    "username": user.username,
-                    "email": user.email,
-                    "first_name": user.first_name,
-                    "last_name": user.last_name,
-                    "phone_number": user.phone_number
-                },
-                "access_token": access_token.token.unwrap(),
+    // Generate JWT token
+    let access_token = match generate_jwt_token(
+        &pool.keyring,
+        TokenKind::Access,
+        user.user_id,
+        session_id,
+        Some(&user.role),
+        1,
+    ) {
+        Ok(token) => token,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({ "error": err.to_string() }))
+        }
+    };
+
+    // Generate refresh token
+    let refresh_token =
+        match issue_refresh_token(&pool.db, &pool.keyring, user.user_id, session_id, 7).await {
+            Ok(token) => token,
+            Err(err) => {
+                return HttpResponse::InternalServerError()
+                    .json(json!({ "error": err.to_string() }))
+            }
+        };
+
+    // Build access and refresh cookies
+    let refresh_cookie = Cookie::build("refresh_token", refresh_token.token.clone().unwrap())
+        .http_only(true)
+        .secure(true)
+        .same_site(actix_web::cookie::SameSite::None)
+        .max_age(Duration::days(7))
+        .finish();
+    let access_cookie = Cookie::build("access_token", access_token.token.clone().unwrap())
+        .http_only(true)
+        .secure(true)
+        .same_site(actix_web::cookie::SameSite::None)
+        .max_age(Duration::days(1))
+        .finish();
+
+    // Return response with cookies and user data
+    HttpResponse::Ok()
+        .cookie(access_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({
+            "status": "success",
+            "data": {
+                "user_id" : user.user_id,
+                "username": user.username,
+                "email": user.email,
+                "first_name": user.first_name,
+                "last_name": user.last_name,
+                "phone_number": user.phone_number,
+                "role": user.role,
+                "email_verified_at": user.email_verified_at
+            },
+            "access_token": access_token.token.unwrap(),
+            "mfa_enrollment_required": mfa_enrollment_required,
+        }))
+}
+
+#[post("/api/v1/auth/password/forgot")]
+async fn forgot_password(data: Json<PasswordForgot>, pool: Data<AppState>) -> impl Responder {
+    // The lookup and email happen in the background so that neither the
+    // response nor its timing reveals whether the address is registered
+    let email = data.into_inner().email;
+    let pool = pool.clone();
+    actix_rt::spawn(async move {
+        if let Err(err) = request_password_reset(&pool.db, pool.mailer.as_ref(), &email).await {
+            log::warn!("Failed to send password reset email: {}", err);
+        }
+    });
+
+    HttpResponse::Ok().json(json!({
+        "status" : "success",
+        "data" : "If an account exists for that email, a reset link has been sent"
+    }))
+}
+
+#[post("/api/v1/auth/password/reset")]
+async fn reset_password(data: Json<PasswordReset>, pool: Data<AppState>) -> impl Responder {
+    let data = data.into_inner();
+    if data.password.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Password must not be empty"
+        }));
+    }
+    let password_hash = match hash(&data.password, DEFAULT_COST) {
+        Ok(hashed) => hashed,
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
             }))
-    } else {
-        // Password doesn't match
-        HttpResponse::BadRequest().json(json!({ "error": "Wrong Password" }))
+        }
+    };
+
+    match redeem_password_reset(&pool.db, &data.token, &password_hash).await {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : "Password updated, please log in again"
+        })),
+        Ok(false) => HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Invalid or expired reset link"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
     }
 }
 
-#[get("/refresh")]
+#[routes]
+#[post("/api/v1/auth/refresh")]
+#[get("/refresh")] // legacy
 async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) -> impl Responder {
     // Extract refresh token from Authorization header
     let refresh_token = match req.cookie("refresh_token") {
@@ -185,15 +413,24 @@ async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) ->
                 .json(serde_json::json!({"status": "fail", "message": "Cookie not present"}));
         }
     };
-    // Verify refresh token
-    let refresh_token_details = match verify_jwt_token(
-        &env::var("REFRESH_SECRET_KEY").unwrap(),
-        &refresh_token,
-    ) {
+    // Rotate the refresh token; each one can be exchanged exactly once
+    let refresh_token_details = match rotate_refresh_token(&pool.db, &pool.keyring, &refresh_token)
+        .await
+    {
         Ok(token_details) => token_details,
-        Err(e) => {
+        Err(RefreshError::Reused) => {
+            return HttpResponse::Forbidden().json(json!({
+                "status": "fail",
+                "message": "Refresh token reuse detected, please log in again"
+            }));
+        }
+        Err(RefreshError::Invalid) => {
             return HttpResponse::Forbidden()
-                .json(serde_json::json!({"status": "fail", "message": format!("Failed to verify refresh token: {:?}", e)}));
+                .json(json!({"status": "fail", "message": "Invalid or revoked refresh token"}));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(serde_json::json!({"status": "fail", "message": format!("Failed to verify refresh token: {}", e)}));
         }
     };
     // Fetch user data
@@ -214,8 +451,11 @@ async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) ->
     };
     // Generate new access token
     let access_token = match generate_jwt_token(
+        &pool.keyring,
+        TokenKind::Access,
         refresh_token_details.user_id,
-        &env::var("ACCESS_SECRET_KEY").unwrap(),
+        refresh_token_details.session_id.unwrap(),
+        Some(&user.role),
         1,
     ) {
         Ok(token_details) => token_details,
@@ -224,7 +464,16 @@ async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) ->
                 .json(serde_json::json!({"status": "fail", "message": format!("Failed to generate access token: {:?}", e)}));
         }
     };
-    // Set new access token cookie
+    // Set new access and refresh token cookies
+    let refresh_cookie = Cookie::build(
+        "refresh_token",
+        refresh_token_details.token.clone().unwrap(),
+    )
+    .http_only(true)
+    .secure(true)
+    .same_site(actix_web::cookie::SameSite::None)
+    .max_age(Duration::days(7))
+    .finish();
     let access_cookie = Cookie::build("access_token", access_token.token.clone().unwrap())
         .http_only(true)
         .secure(true)
@@ -233,7 +482,7 @@ async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) ->
         .finish();
     // Return response with new access token, refresh token, and user data
     HttpResponse::Ok()
-        // .cookie(refresh_cookie)
+        .cookie(refresh_cookie)
         .cookie(access_cookie)
         .json(json!({
             "status": "success",
@@ -243,14 +492,26 @@ async fn refresh_access_token_handler(req: HttpRequest, pool: Data<AppState>) ->
                 "email": user.email,
                 "first_name": user.first_name,
                 "last_name": user.last_name,
-                "phone_number": user.phone_number
+                "phone_number": user.phone_number,
+                "role": user.role,
+                "email_verified_at": user.email_verified_at
             },
             "access_token": access_token.token,
         }))
 }
 
-#[get("/logout")]
-async fn logout() -> impl Responder {
+#[routes]
+#[post("/api/v1/auth/logout")]
+#[get("/logout")] // legacy
+async fn logout(req: HttpRequest, pool: Data<AppState>) -> impl Responder {
+    // Revoke the refresh token server-side so a copied cookie stops working
+    if let Some(c) = req.cookie("refresh_token") {
+        if let Err(err) = revoke_refresh_token(&pool.db, c.value()).await {
+            return HttpResponse::InternalServerError().json(
+                json!({ "status": "fail", "message": format!("Failed to log out: {:?}", err) }),
+            );
+        }
+    }
     // Set new access token cookie
     let access_cookie = Cookie::build("access_token", "")
         .http_only(true)
@@ -275,7 +536,9 @@ async fn logout() -> impl Responder {
 }
 // Function to fetch user data by ID
 
-#[delete("/delete")]
+#[routes]
+#[delete("/api/v1/users/me")]
+#[delete("/delete")] // legacy
 async fn delete_user(pool: Data<AppState>, jwt_guard: jwt_auth::JwtMiddleware) -> impl Responder {
     let user_id = jwt_guard.user.user_id;
     match sqlx::query!("DELETE FROM users where user_id = $1", user_id)
@@ -291,3 +554,282 @@ async fn delete_user(pool: Data<AppState>, jwt_guard: jwt_auth::JwtMiddleware) -
         })),
     }
 }
+
+#[patch("/api/v1/users/{user_id}/role")]
+async fn update_user_role(
+    user_id: Path<Uuid>,
+    data: Json<RoleUpdate>,
+    pool: Data<AppState>,
+    _: RequireRole<Admin>,
+) -> impl Responder {
+    let role = match Role::parse(&data.role) {
+        Some(role) => role,
+        None => {
+            return HttpResponse::BadRequest()
+                .json(json!({ "status" : "fail", "error" : "Invalid role" }))
+        }
+    };
+    match sqlx::query!(
+        "UPDATE users SET role = $1 WHERE user_id = $2",
+        role.as_str(),
+        user_id.into_inner()
+    )
+    .execute(&pool.db)
+    .await
+    {
+        Ok(result) if result.rows_affected() == 0 => HttpResponse::NotFound().json(json!({
+            "status" : "fail",
+            "error" : "User not found"
+        })),
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : { "role" : role.as_str() }
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[post("/api/v1/auth/verify-email")]
+async fn verify_email(data: Json<EmailVerification>, pool: Data<AppState>) -> impl Responder {
+    match verify_email_token(&pool.db, &pool.keyring, &data.token).await {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : "Email verified"
+        })),
+        Ok(false) => HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Invalid or expired verification link"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err.to_string()
+        })),
+    }
+}
+
+#[post("/api/v1/auth/resend-verification")]
+async fn resend_verification(
+    pool: Data<AppState>,
+    jwt_guard: jwt_auth::JwtMiddleware,
+) -> impl Responder {
+    let user = jwt_guard.user;
+    if user.email_verified_at.is_some() {
+        return HttpResponse::BadRequest().json(json!({
+            "status" : "fail",
+            "error" : "Email is already verified"
+        }));
+    }
+
+    // One email a minute is plenty
+    match sqlx::query_scalar!(
+        "SELECT EXISTS (
+            SELECT 1 FROM email_verifications
+            WHERE user_id = $1 AND created_at > NOW() - INTERVAL '1 minute'
+        )",
+        user.user_id
+    )
+    .fetch_one(&pool.db)
+    .await
+    {
+        Ok(Some(true)) => {
+            return HttpResponse::TooManyRequests().json(json!({
+                "status" : "fail",
+                "error" : "Please wait a minute before requesting another email"
+            }))
+        }
+        Ok(_) => {}
+        Err(err) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "status" : "fail",
+                "error" : err.to_string()
+            }))
+        }
+    }
+
+    match send_verification_email(&pool.db, pool.mailer.as_ref(), &pool.keyring, &user).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status" : "success",
+            "data" : "Verification email sent"
+        })),
+        Err(err) => HttpResponse::InternalServerError().json(json!({
+            "status" : "fail",
+            "error" : err
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{create_session, create_user, delete_users, test_state};
+    use actix_web::{dev::ServiceResponse, http::StatusCode, test, App};
+
+    // Logs a user in and returns their session with its first refresh token
+    async fn logged_in(state: &AppState, user: &User) -> (Uuid, String) {
+        let session_id = create_session(state, user).await;
+        let token = issue_refresh_token(&state.db, &state.keyring, user.user_id, session_id, 7)
+            .await
+            .unwrap();
+        (session_id, token.token.unwrap())
+    }
+
+    fn refresh(refresh_token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/v1/auth/refresh")
+            .cookie(Cookie::new("refresh_token", refresh_token.to_string()))
+    }
+
+    fn refresh_cookie(response: &ServiceResponse) -> String {
+        response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == "refresh_token")
+            .unwrap()
+            .value()
+            .to_string()
+    }
+
+    async fn session_revoked(state: &AppState, session_id: Uuid) -> bool {
+        sqlx::query_scalar!(
+            "SELECT revoked_at IS NOT NULL AS \"revoked!\" FROM sessions WHERE session_id = $1",
+            session_id
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn refresh_rotates_the_token() {
+        let state = test_state().await;
+        let user = create_user(&state, Role::Attendee).await;
+        let (session_id, first) = logged_in(&state, &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(refresh_access_token_handler),
+        )
+        .await;
+
+        let response = test::call_service(&app, refresh(&first).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let second = refresh_cookie(&response);
+        assert_ne!(second, first);
+
+        let used_at = sqlx::query_scalar!(
+            "SELECT used_at FROM refresh_tokens WHERE token_hash = $1",
+            crate::refresh_token::hash_token(&first)
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        assert!(used_at.is_some());
+        assert!(!session_revoked(&state, session_id).await);
+        assert_eq!(
+            test::call_service(&app, refresh(&second).to_request())
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        delete_users(&state, &[&user]).await;
+    }
+
+    #[actix_web::test]
+    async fn replaying_a_rotated_token_revokes_the_family() {
+        let state = test_state().await;
+        let user = create_user(&state, Role::Attendee).await;
+        let (session_id, first) = logged_in(&state, &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(refresh_access_token_handler),
+        )
+        .await;
+
+        let second = refresh_cookie(&test::call_service(&app, refresh(&first).to_request()).await);
+        let replay = test::call_service(&app, refresh(&first).to_request()).await;
+        assert_eq!(replay.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(replay).await;
+        assert_eq!(
+            body["message"],
+            "Refresh token reuse detected, please log in again"
+        );
+
+        // The token the legitimate holder got is gone with the rest of the family
+        assert!(session_revoked(&state, session_id).await);
+        assert_eq!(
+            test::call_service(&app, refresh(&second).to_request())
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+
+        delete_users(&state, &[&user]).await;
+    }
+
+    #[actix_web::test]
+    async fn logout_revokes_the_session() {
+        let state = test_state().await;
+        let user = create_user(&state, Role::Attendee).await;
+        let (session_id, token) = logged_in(&state, &user).await;
+        let (other_session_id, other_token) = logged_in(&state, &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(refresh_access_token_handler)
+                .service(logout),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/auth/logout")
+            .cookie(Cookie::new("refresh_token", token.clone()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        assert!(session_revoked(&state, session_id).await);
+        assert_eq!(
+            test::call_service(&app, refresh(&token).to_request())
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+        // Other logins of the same user carry on
+        assert!(!session_revoked(&state, other_session_id).await);
+        assert_eq!(
+            test::call_service(&app, refresh(&other_token).to_request())
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        delete_users(&state, &[&user]).await;
+    }
+
+    #[actix_web::test]
+    async fn revoked_token_is_rejected() {
+        let state = test_state().await;
+        let user = create_user(&state, Role::Attendee).await;
+        let (session_id, token) = logged_in(&state, &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(refresh_access_token_handler),
+        )
+        .await;
+
+        crate::session::revoke_session(&state.db, session_id)
+            .await
+            .unwrap();
+        let response = test::call_service(&app, refresh(&token).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["message"], "Invalid or revoked refresh token");
+
+        delete_users(&state, &[&user]).await;
+    }
+}
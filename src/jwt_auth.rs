@@ -2,21 +2,19 @@ use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
 use actix_web::{dev::Payload, Error as ActixWebError};
 use actix_web::{web, FromRequest, HttpRequest};
 use core::fmt;
-use futures::executor::block_on;
+use futures::future::LocalBoxFuture;
 use serde::{Deserialize, Serialize};
-use std::{
-    env,
-    future::{ready, Ready},
-};
+use std::future::ready;
 
+use crate::keyring::TokenKind;
 use crate::models::AppState;
 use crate::models::User;
 use crate::token::verify_jwt_token;
 
 #[derive(Debug, Serialize)]
-struct ErrorResponse {
-    status: String,
-    message: String,
+pub struct ErrorResponse {
+    pub status: String,
+    pub message: String,
 }
 
 impl fmt::Display for ErrorResponse {
@@ -28,13 +26,14 @@ impl fmt::Display for ErrorResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JwtMiddleware {
     pub user: User,
+    pub session_id: uuid::Uuid,
 }
 
 impl FromRequest for JwtMiddleware {
     type Error = ActixWebError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let data = req.app_data::<web::Data<AppState>>().unwrap();
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
 
         // let access_token = req
         //     .cookie("access_token")
@@ -79,36 +78,66 @@ impl FromRequest for JwtMiddleware {
                     status: "fail".to_string(),
                     message: "Access token not found in headers or cookies".to_string(),
                 };
-                return ready(Err(ErrorUnauthorized(json_error)));
+                return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
             }
         };
 
         let access_token_details =
-            match verify_jwt_token(&env::var("ACCESS_SECRET_KEY").unwrap(), &access_token) {
+            match verify_jwt_token(&data.keyring, TokenKind::Access, &access_token) {
                 Ok(token_details) => token_details,
                 Err(e) => {
                     let json_error = ErrorResponse {
                         status: "fail".to_string(),
                         message: format!("{:?}", e),
                     };
-                    return ready(Err(ErrorUnauthorized(json_error)));
+                    return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
                 }
             };
 
         let user_id = uuid::Uuid::parse_str(&access_token_details.user_id.to_string()).unwrap();
+        let session_id = match access_token_details.session_id {
+            Some(session_id) => session_id,
+            None => {
+                let json_error = ErrorResponse {
+                    status: "fail".to_string(),
+                    message: "Token is not tied to a session, please refresh".to_string(),
+                };
+                return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
+            }
+        };
 
-        let user_exists_result = async move {
-            let query_result =
-                sqlx::query_as!(User, "SELECT * FROM users WHERE user_id = $1", user_id)
-                    .fetch_optional(&data.db)
-                    .await;
+        Box::pin(async move {
+            // The user must still exist and the session must not have been revoked
+            let query_result = sqlx::query_as!(
+                User,
+                "SELECT u.* FROM users u
+                JOIN sessions s ON s.user_id = u.user_id
+                WHERE u.user_id = $1 AND s.session_id = $2 AND s.revoked_at IS NULL",
+                user_id,
+                session_id
+            )
+            .fetch_optional(&data.db)
+            .await;
 
             match query_result {
-                Ok(Some(user)) => Ok(user),
+                Ok(Some(user)) => {
+                    // Keep last_used_at roughly current without a write on every request
+                    if let Err(err) = sqlx::query!(
+                        "UPDATE sessions SET last_used_at = NOW()
+                        WHERE session_id = $1 AND last_used_at < NOW() - INTERVAL '1 minute'",
+                        session_id
+                    )
+                    .execute(&data.db)
+                    .await
+                    {
+                        log::warn!("Failed to update session {}: {}", session_id, err);
+                    }
+                    Ok(JwtMiddleware { user, session_id })
+                }
                 Ok(None) => {
                     let json_error = ErrorResponse {
                         status: "fail".to_string(),
-                        message: "the user belonging to this token no logger exists".to_string(),
+                        message: "the session belonging to this token was revoked or the user no longer exists".to_string(),
                     };
                     Err(ErrorUnauthorized(json_error))
                 }
@@ -120,11 +149,6 @@ impl FromRequest for JwtMiddleware {
                     Err(ErrorInternalServerError(json_error))
                 }
             }
-        };
-
-        match block_on(user_exists_result) {
-            Ok(user) => ready(Ok(JwtMiddleware { user })),
-            Err(error) => ready(Err(error)),
-        }
+        })
     }
 }
@@ -0,0 +1,301 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPublicKey;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::{env, fs};
+
+// DER prefix of an Ed25519 SubjectPublicKeyInfo; the raw key follows it
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+// What a token is for, carried in its `aud` claim so one can't stand in for the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Access,
+    Refresh,
+    // Signed ticket credential shown as a QR code at the door
+    Ticket,
+    // List of an event's valid bookings for scanners working offline
+    ScanManifest,
+}
+
+impl TokenKind {
+    pub fn audience(&self) -> &'static str {
+        match self {
+            TokenKind::Access => "kriyapass-api",
+            TokenKind::Refresh => "kriyapass-refresh",
+            TokenKind::Ticket => "kriyapass-ticket",
+            TokenKind::ScanManifest => "kriyapass-scan-manifest",
+        }
+    }
+}
+
+struct VerificationKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    // Public JWK published at /.well-known/jwks.json; None for shared secrets
+    jwk: Option<Value>,
+}
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+// Signs the links in verification emails. Only we ever check them, so a
+// shared secret does.
+struct EmailKey {
+    signing: EncodingKey,
+    verification: DecodingKey,
+}
+
+impl EmailKey {
+    fn new(secret: &str) -> Self {
+        EmailKey {
+            signing: EncodingKey::from_secret(secret.as_bytes()),
+            verification: DecodingKey::from_secret(secret.as_bytes()),
+        }
+    }
+}
+
+/// Keys used to sign and verify JWTs. One key signs; every key in the ring
+/// verifies, so tokens signed by a retired key keep working until they expire.
+pub struct Keyring {
+    pub issuer: String,
+    access: SigningKey,
+    refresh: SigningKey,
+    ticket: SigningKey,
+    verification: Vec<VerificationKey>,
+    email: EmailKey,
+}
+
+impl Keyring {
+    /// Loads asymmetric keys from JWT_KEYS_DIR when it is set. Each key is a
+    /// `<kid>.<rs256|eddsa>.pub.pem` public key; JWT_SIGNING_KID names the
+    /// one that signs, whose private key must sit next to it as
+    /// `<kid>.<alg>.key.pem`. Without JWT_KEYS_DIR, tokens fall back to HS256
+    /// with ACCESS_SECRET_KEY and REFRESH_SECRET_KEY. Email verification
+    /// links are always signed with EMAIL_VERIFICATION_SECRET_KEY.
+    pub fn from_env() -> Result<Self, String> {
+        let issuer = env::var("JWT_ISSUER").unwrap_or_else(|_| "kriyapass".to_string());
+        let email = EmailKey::new(
+            &env::var("EMAIL_VERIFICATION_SECRET_KEY")
+                .map_err(|_| "EMAIL_VERIFICATION_SECRET_KEY is not set")?,
+        );
+        let keyring = match env::var("JWT_KEYS_DIR") {
+            Ok(dir) => {
+                let signing_kid = env::var("JWT_SIGNING_KID")
+                    .map_err(|_| "JWT_SIGNING_KID must be set with JWT_KEYS_DIR".to_string())?;
+                Self::load_dir(issuer, Path::new(&dir), &signing_kid, email)?
+            }
+            Err(_) => Self::shared_secret(
+                issuer,
+                &env::var("ACCESS_SECRET_KEY").map_err(|_| "ACCESS_SECRET_KEY is not set")?,
+                &env::var("REFRESH_SECRET_KEY").map_err(|_| "REFRESH_SECRET_KEY is not set")?,
+                email,
+            ),
+        };
+        Ok(keyring)
+    }
+
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self::shared_secret(
+            "kriyapass".to_string(),
+            "test-access",
+            "test-refresh",
+            EmailKey::new("test-email"),
+        )
+    }
+
+    fn shared_secret(
+        issuer: String,
+        access_secret: &str,
+        refresh_secret: &str,
+        email: EmailKey,
+    ) -> Self {
+        let secret_key = |kid: &str, secret: &str| {
+            (
+                SigningKey {
+                    kid: kid.to_string(),
+                    algorithm: Algorithm::HS256,
+                    key: EncodingKey::from_secret(secret.as_bytes()),
+                },
+                VerificationKey {
+                    kid: kid.to_string(),
+                    algorithm: Algorithm::HS256,
+                    key: DecodingKey::from_secret(secret.as_bytes()),
+                    jwk: None,
+                },
+            )
+        };
+        let (access, access_verification) = secret_key("access", access_secret);
+        let (refresh, refresh_verification) = secret_key("refresh", refresh_secret);
+        // Ticket credentials and scan manifests share the access secret; `aud`
+        // tells them apart
+        let (ticket, _) = secret_key("access", access_secret);
+        Keyring {
+            issuer,
+            access,
+            refresh,
+            ticket,
+            verification: vec![access_verification, refresh_verification],
+            email,
+        }
+    }
+
+    fn load_dir(
+        issuer: String,
+        dir: &Path,
+        signing_kid: &str,
+        email: EmailKey,
+    ) -> Result<Self, String> {
+        let mut verification = Vec::new();
+        let mut signing = None;
+        let entries = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let Some((kid, alg)) = name
+                .strip_suffix(".pub.pem")
+                .and_then(|stem| stem.rsplit_once('.'))
+            else {
+                continue;
+            };
+            let algorithm = match alg {
+                "rs256" => Algorithm::RS256,
+                "eddsa" => Algorithm::EdDSA,
+                _ => return Err(format!("{}: unsupported algorithm {}", name, alg)),
+            };
+            let public_pem = fs::read(&path).map_err(|err| format!("{}: {}", name, err))?;
+            let (key, jwk) = public_key(kid, algorithm, &public_pem)
+                .map_err(|err| format!("{}: {}", name, err))?;
+
+            if kid == signing_kid {
+                let private_path = dir.join(format!("{}.{}.key.pem", kid, alg));
+                let private_pem = fs::read(&private_path)
+                    .map_err(|err| format!("{}: {}", private_path.display(), err))?;
+                let key = match algorithm {
+                    Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_pem),
+                    _ => EncodingKey::from_ed_pem(&private_pem),
+                }
+                .map_err(|err| format!("{}: {}", private_path.display(), err))?;
+                signing = Some((algorithm, key));
+            }
+            verification.push(VerificationKey {
+                kid: kid.to_string(),
+                algorithm,
+                key,
+                jwk: Some(jwk),
+            });
+        }
+
+        let (algorithm, key) =
+            signing.ok_or_else(|| format!("No public key found for kid {}", signing_kid))?;
+        // Every token kind shares the signing key; `aud` tells them apart
+        let signing_key = || SigningKey {
+            kid: signing_kid.to_string(),
+            algorithm,
+            key: key.clone(),
+        };
+        Ok(Keyring {
+            issuer,
+            access: signing_key(),
+            refresh: signing_key(),
+            ticket: signing_key(),
+            verification,
+            email,
+        })
+    }
+
+    pub fn signing_key(&self, kind: TokenKind) -> (&str, Algorithm, &EncodingKey) {
+        let key = match kind {
+            TokenKind::Access => &self.access,
+            TokenKind::Refresh => &self.refresh,
+            TokenKind::Ticket | TokenKind::ScanManifest => &self.ticket,
+        };
+        (&key.kid, key.algorithm, &key.key)
+    }
+
+    /// The key for a token's `kid`. Tokens without one are checked against
+    /// the current signing key.
+    pub fn verification_key(
+        &self,
+        kind: TokenKind,
+        kid: Option<&str>,
+    ) -> Option<(Algorithm, &DecodingKey)> {
+        let kid = kid.unwrap_or_else(|| self.signing_key(kind).0);
+        self.verification
+            .iter()
+            .find(|key| key.kid == kid)
+            .map(|key| (key.algorithm, &key.key))
+    }
+
+    pub fn email_signing_key(&self) -> &EncodingKey {
+        &self.email.signing
+    }
+
+    pub fn email_verification_key(&self) -> &DecodingKey {
+        &self.email.verification
+    }
+
+    /// JWK Set of the public keys, for services that verify our tokens.
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self
+            .verification
+            .iter()
+            .filter_map(|key| key.jwk.as_ref())
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+fn public_key(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Result<(DecodingKey, Value), String> {
+    match algorithm {
+        Algorithm::RS256 => {
+            let pem = std::str::from_utf8(pem).map_err(|err| err.to_string())?;
+            let public = RsaPublicKey::from_public_key_pem(pem).map_err(|err| err.to_string())?;
+            let n = URL_SAFE_NO_PAD.encode(public.n().to_bytes_be());
+            let e = URL_SAFE_NO_PAD.encode(public.e().to_bytes_be());
+            let key = DecodingKey::from_rsa_components(&n, &e).map_err(|err| err.to_string())?;
+            let jwk = json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": n,
+                "e": e
+            });
+            Ok((key, jwk))
+        }
+        _ => {
+            let der = pem::parse(pem).map_err(|err| err.to_string())?;
+            let raw = der
+                .contents()
+                .strip_prefix(&ED25519_SPKI_PREFIX[..])
+                .filter(|raw| raw.len() == 32)
+                .ok_or("not an Ed25519 public key")?;
+            let x = URL_SAFE_NO_PAD.encode(raw);
+            let key = DecodingKey::from_ed_components(&x).map_err(|err| err.to_string())?;
+            let jwk = json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": kid,
+                "x": x
+            });
+            Ok((key, jwk))
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use chrono::Utc;
+use sqlx::{postgres::Postgres, Pool};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    // Failures allowed per account, then per client IP, before a lockout
+    pub max_account_failures: u32,
+    pub max_ip_failures: u32,
+    // Failures older than this are forgotten
+    pub failure_window: Duration,
+    // The first lockout lasts this long; each further one doubles, up to max_lockout
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_account_failures: 5,
+            max_ip_failures: 20,
+            failure_window: Duration::from_secs(15 * 60),
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// Reads LOGIN_MAX_ACCOUNT_FAILURES, LOGIN_MAX_IP_FAILURES,
+    /// LOGIN_FAILURE_WINDOW_SECS, LOGIN_LOCKOUT_SECS and LOGIN_MAX_LOCKOUT_SECS,
+    /// falling back to the defaults for any that are unset.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            env::var(name).ok().and_then(|value| value.parse().ok())
+        }
+        let defaults = ThrottleConfig::default();
+        ThrottleConfig {
+            max_account_failures: var("LOGIN_MAX_ACCOUNT_FAILURES")
+                .unwrap_or(defaults.max_account_failures),
+            max_ip_failures: var("LOGIN_MAX_IP_FAILURES").unwrap_or(defaults.max_ip_failures),
+            failure_window: var("LOGIN_FAILURE_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.failure_window),
+            lockout: var("LOGIN_LOCKOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.lockout),
+            max_lockout: var("LOGIN_MAX_LOCKOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.max_lockout),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptKey<'a> {
+    Account(&'a str),
+    Ip(&'a str),
+}
+
+impl AttemptKey<'_> {
+    fn store_key(&self) -> String {
+        match self {
+            // Emails are matched case-insensitively so casing can't dodge the limit
+            AttemptKey::Account(email) => format!("account:{}", email.trim().to_lowercase()),
+            AttemptKey::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AttemptRecord {
+    pub failures: u32,
+    pub last_failure: Instant,
+    pub locked_until: Option<Instant>,
+    // Lockouts so far, which sets how long the next one lasts
+    pub lockouts: u32,
+}
+
+/// Where failed-attempt counters live. AppState holds a `LoginThrottle` over
+/// one of these so a shared store can replace the in-memory one when the
+/// API runs on more than one instance.
+pub trait AttemptStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<AttemptRecord>;
+    // Replaces the record for `key` with `update` applied to it, as one step
+    // that no other change to the key can land in between, and returns the
+    // new record. Stores that retry on conflict may call `update` again.
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<AttemptRecord>) -> AttemptRecord,
+    ) -> AttemptRecord;
+    fn remove(&self, key: &str);
+    // Drops every record for which `keep` returns false
+    fn retain(&self, keep: &dyn Fn(&AttemptRecord) -> bool);
+}
+
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    records: Mutex<HashMap<String, AttemptRecord>>,
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn get(&self, key: &str) -> Option<AttemptRecord> {
+        self.records.lock().unwrap().get(key).copied()
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        update: &mut dyn FnMut(Option<AttemptRecord>) -> AttemptRecord,
+    ) -> AttemptRecord {
+        let mut records = self.records.lock().unwrap();
+        let record = update(records.get(key).copied());
+        records.insert(key.to_string(), record);
+        record
+    }
+
+    fn remove(&self, key: &str) {
+        self.records.lock().unwrap().remove(key);
+    }
+
+    fn retain(&self, keep: &dyn Fn(&AttemptRecord) -> bool) {
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|_, record| keep(record));
+    }
+}
+
+// Returned when a failure pushes a key over its limit
+#[derive(Debug, Clone, Copy)]
+pub struct Lockout {
+    pub failures: u32,
+    pub duration: Duration,
+}
+
+pub struct LoginThrottle {
+    config: ThrottleConfig,
+    store: Box<dyn AttemptStore>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: ThrottleConfig, store: Box<dyn AttemptStore>) -> Self {
+        LoginThrottle { config, store }
+    }
+
+    fn limit(&self, key: &AttemptKey) -> u32 {
+        match key {
+            AttemptKey::Account(_) => self.config.max_account_failures,
+            AttemptKey::Ip(_) => self.config.max_ip_failures,
+        }
+    }
+
+    /// How long until `keys` may try again, if any of them is locked out.
+    pub fn retry_after(&self, keys: &[AttemptKey], now: Instant) -> Option<Duration> {
+        keys.iter()
+            .filter_map(|key| self.store.get(&key.store_key())?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    /// Counts a failed attempt against `key`, locking it out once it reaches
+    /// its limit.
+    pub fn record_failure(&self, key: AttemptKey, now: Instant) -> Option<Lockout> {
+        let mut lockout = None;
+        self.store.update(&key.store_key(), &mut |record| {
+            let (record, locked) = self.count_failure(&key, record, now);
+            lockout = locked;
+            record
+        });
+        lockout
+    }
+
+    fn count_failure(
+        &self,
+        key: &AttemptKey,
+        record: Option<AttemptRecord>,
+        now: Instant,
+    ) -> (AttemptRecord, Option<Lockout>) {
+        let mut record = match record {
+            Some(record)
+                if now.duration_since(record.last_failure) <= self.config.failure_window =>
+            {
+                record
+            }
+            // Keep escalating while a recent lockout is on record
+            Some(record)
+                if record
+                    .locked_until
+                    .is_some_and(|until| now < until + self.config.max_lockout) =>
+            {
+                AttemptRecord {
+                    failures: 0,
+                    ..record
+                }
+            }
+            _ => AttemptRecord {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+                lockouts: 0,
+            },
+        };
+
+        record.failures += 1;
+        record.last_failure = now;
+        let lockout = if record.failures >= self.limit(key) {
+            let duration = self
+                .config
+                .lockout
+                .saturating_mul(2u32.saturating_pow(record.lockouts))
+                .min(self.config.max_lockout);
+            let failures = record.failures;
+            record.locked_until = Some(now + duration);
+            record.lockouts += 1;
+            record.failures = 0;
+            Some(Lockout { failures, duration })
+        } else {
+            None
+        };
+        (record, lockout)
+    }
+
+    pub fn record_success(&self, key: AttemptKey) {
+        self.store.remove(&key.store_key());
+    }
+
+    /// Forgets records that no longer affect any login, so the store doesn't
+    /// grow with every address that ever mistyped a password.
+    pub fn prune(&self, now: Instant) {
+        let config = &self.config;
+        self.store.retain(&|record| {
+            now.duration_since(record.last_failure) <= config.failure_window
+                || record
+                    .locked_until
+                    .is_some_and(|until| now < until + config.max_lockout)
+        });
+    }
+}
+
+/// Writes the audit entry for a lockout.
+pub async fn record_lockout(
+    db: &Pool<Postgres>,
+    key: AttemptKey<'_>,
+    user_id: Option<Uuid>,
+    ip_address: &str,
+    lockout: Lockout,
+) -> Result<(), sqlx::Error> {
+    let (scope, subject) = match key {
+        AttemptKey::Account(email) => ("account", email.trim().to_lowercase()),
+        AttemptKey::Ip(ip) => ("ip", ip.to_string()),
+    };
+    let locked_until =
+        (Utc::now() + chrono::Duration::from_std(lockout.duration).unwrap_or_default()).naive_utc();
+    sqlx::query!(
+        "INSERT INTO login_lockouts
+            (lockout_id, scope, subject, user_id, ip_address, failures, locked_until)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        Uuid::new_v4(),
+        scope,
+        subject,
+        user_id,
+        ip_address,
+        lockout.failures as i32,
+        locked_until
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "someone@example.com";
+    const IP: &str = "203.0.113.7";
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(
+            ThrottleConfig {
+                max_account_failures: 3,
+                max_ip_failures: 5,
+                failure_window: Duration::from_secs(60),
+                lockout: Duration::from_secs(30),
+                max_lockout: Duration::from_secs(120),
+            },
+            Box::<MemoryAttemptStore>::default(),
+        )
+    }
+
+    // Records `count` failures for `key`, returning the last lockout
+    fn fail(
+        throttle: &LoginThrottle,
+        key: AttemptKey,
+        count: u32,
+        now: Instant,
+    ) -> Option<Lockout> {
+        let mut lockout = None;
+        for _ in 0..count {
+            lockout = throttle.record_failure(key, now);
+        }
+        lockout
+    }
+
+    #[test]
+    fn locks_out_at_the_limit() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let account = AttemptKey::Account(EMAIL);
+
+        assert!(fail(&throttle, account, 2, now).is_none());
+        assert_eq!(throttle.retry_after(&[account], now), None);
+
+        let lockout = throttle.record_failure(account, now).unwrap();
+        assert_eq!(lockout.failures, 3);
+        assert_eq!(lockout.duration, Duration::from_secs(30));
+        assert_eq!(
+            throttle.retry_after(&[account], now),
+            Some(Duration::from_secs(30))
+        );
+        // Casing doesn't make it another account
+        assert!(throttle
+            .retry_after(&[AttemptKey::Account("Someone@Example.com")], now)
+            .is_some());
+    }
+
+    #[test]
+    fn old_failures_and_lockouts_expire() {
+        let throttle = throttle();
+        let start = Instant::now();
+        let account = AttemptKey::Account(EMAIL);
+
+        // Failures outside the window are forgotten
+        fail(&throttle, account, 2, start);
+        let later = start + Duration::from_secs(61);
+        assert!(fail(&throttle, account, 2, later).is_none());
+
+        // A lockout ends after its duration
+        let lockout = throttle.record_failure(account, later).unwrap();
+        assert!(throttle.retry_after(&[account], later).is_some());
+        let after = later + lockout.duration;
+        assert_eq!(throttle.retry_after(&[account], after), None);
+
+        // The next one, while the first is still recent, lasts twice as long
+        let lockout = fail(&throttle, account, 3, after).unwrap();
+        assert_eq!(lockout.duration, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let account = AttemptKey::Account(EMAIL);
+
+        fail(&throttle, account, 2, now);
+        throttle.record_success(account);
+        assert!(fail(&throttle, account, 2, now).is_none());
+
+        fail(&throttle, account, 1, now);
+        assert!(throttle.retry_after(&[account], now).is_some());
+        throttle.record_success(account);
+        assert_eq!(throttle.retry_after(&[account], now), None);
+    }
+
+    #[test]
+    fn accounts_and_addresses_are_counted_apart() {
+        let throttle = throttle();
+        let now = Instant::now();
+        let account = AttemptKey::Account(EMAIL);
+        let ip = AttemptKey::Ip(IP);
+
+        fail(&throttle, account, 3, now);
+        assert!(throttle.retry_after(&[account], now).is_some());
+        assert_eq!(throttle.retry_after(&[ip], now), None);
+        assert_eq!(
+            throttle.retry_after(&[AttemptKey::Account("other@example.com")], now),
+            None
+        );
+
+        // The address has its own, higher limit
+        assert!(fail(&throttle, ip, 4, now).is_none());
+        assert!(throttle.record_failure(ip, now).is_some());
+        assert!(throttle.retry_after(&[ip], now).is_some());
+        assert!(throttle.retry_after(&[account, ip], now).is_some());
+    }
+}
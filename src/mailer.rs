@@ -0,0 +1,26 @@
+use futures::future::BoxFuture;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Outgoing mail transport. AppState holds one behind an `Arc` so SMTP or an
+/// email API can be swapped in without touching the handlers.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), String>>;
+}
+
+// Writes emails to the log instead of sending them, for local development
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            log::info!("Email to {} | {}\n{}", email.to, email.subject, email.body);
+            Ok(())
+        })
+    }
+}
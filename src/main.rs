@@ -2,83 +2,237 @@
 use actix_cors::Cors;
 use actix_rt::{spawn, time::interval};
 use actix_web::{
-    http::header,
+    dev::Service,
+    http::header::{self, HeaderValue},
     middleware::Logger,
     web::{self, Data},
     App, HttpResponse, HttpServer, Responder,
 };
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::time::Duration; // Add missing imports
                            // Import module
+mod api_key;
+mod check_in;
+mod check_in_feed;
 mod database;
+mod email_verification;
+mod event_changes;
+mod event_listing;
+mod event_schedule;
+mod event_status;
 mod handler;
 mod jwt_auth;
+mod keyring;
+mod login_throttle;
+mod mailer;
 mod models;
+mod money;
+mod oidc;
+mod pagination;
+mod password_reset;
+mod refresh_token;
+mod roles;
+mod session;
+#[cfg(test)]
+mod test_support;
+mod ticket_credential;
 mod token;
+mod totp;
+use crate::check_in_feed::CheckInFeed;
 use crate::database::connect_database;
+use crate::keyring::Keyring;
+use crate::login_throttle::{LoginThrottle, MemoryAttemptStore, ThrottleConfig};
+use crate::mailer::{LogMailer, Mailer};
 use crate::models::AppState;
+use crate::oidc::OidcClient;
 use handler::{
-    booking_handler::{book_ticket, delete_booking, get_bookings, ticket_verification},
+    api_key_handlers::{create_api_key, get_api_keys, revoke_api_key},
+    admission_handlers::{get_admission_credential, get_admissions, update_admission},
+    booking_handler::{
+        book_ticket, delete_booking, get_bookings, scan_ticket_credential, ticket_verification,
+    },
     event_handlers::{
-        check_and_update_events, create_event, delete_event, get_event, get_event_by_user,
-        get_events,
+        add_event_member, cancel_event, check_and_update_events, create_event, delete_event,
+        get_event, get_event_by_user, get_event_changes, get_event_members, get_events,
+        pause_event_sales, postpone_event, publish_event, remove_event_member, search_events,
+        update_event,
+    },
+    jwks_handler::jwks,
+    legacy_handler::delete_by_id,
+    mfa_handlers::{
+        confirm_totp, disable_totp, enroll_totp, set_role_mfa_policy, verify_totp_login,
+    },
+    oidc_handlers::{oidc_authorize, oidc_callback},
+    scan_handlers::{
+        get_check_in_feed, get_check_ins, get_entry_policy, get_scan_manifest, set_entry_policy,
+        sync_scans,
     },
+    session_handlers::{get_sessions, revoke_other_sessions, revoke_user_session},
     ticket_handlers::{delete_ticket, generate_ticket, get_ticket},
-    user_handlers::{add_user, delete_user, get_user, logout, refresh_access_token_handler},
+    user_handlers::{
+        add_user, delete_user, forgot_password, get_user, logout, refresh_access_token_handler,
+        resend_verification, reset_password, update_user_role, verify_email,
+    },
 };
 
 // Main function
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "actix_web=info");
+        std::env::set_var("RUST_LOG", "actix_web=info,kriyapass=info");
     }
     env_logger::init();
     let pool = connect_database().await;
     let poolclone = pool.clone();
+    let mailer: Arc<dyn Mailer> = Arc::new(LogMailer);
+    let mailerclone = mailer.clone();
+    let login_throttle = Arc::new(LoginThrottle::new(
+        ThrottleConfig::from_env(),
+        Box::<MemoryAttemptStore>::default(),
+    ));
+    let login_throttle_clone = login_throttle.clone();
+    let keyring = Arc::new(Keyring::from_env().expect("Failed to load signing keys"));
+    let keyringclone = keyring.clone();
+    let oidc = Arc::new(OidcClient::from_env().expect("Invalid OIDC provider configuration"));
+    let oidcclone = oidc.clone();
+    let check_in_feed = Arc::new(CheckInFeed::default());
+    let check_in_feedclone = check_in_feed.clone();
     spawn(async move {
-        let mut interval = interval(Duration::from_secs(60 * 60 * 12));
+        // Often enough that events are marked completed soon after they end
+        let mut interval = interval(Duration::from_secs(15 * 60));
         loop {
             interval.tick().await;
             check_and_update_events(Data::new(AppState {
                 db: poolclone.clone(),
+                mailer: mailerclone.clone(),
+                login_throttle: login_throttle_clone.clone(),
+                keyring: keyringclone.clone(),
+                oidc: oidcclone.clone(),
+                check_in_feed: check_in_feedclone.clone(),
             }))
             .await;
         }
     });
+    let feed_listener = check_in_feed.clone();
+    let feed_pool = pool.clone();
+    spawn(async move { feed_listener.listen(feed_pool).await });
+    let throttle_pruner = login_throttle.clone();
+    spawn(async move {
+        let mut interval = interval(Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            throttle_pruner.prune(Instant::now());
+        }
+    });
 
     HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://localhost:3000")
             .supports_credentials()
-            .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 header::CONTENT_TYPE,
                 header::AUTHORIZATION,
                 header::ACCEPT,
                 header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                header::HeaderName::from_static("x-api-key"),
             ]);
         // Capture pool by reference
         App::new()
-            .app_data(Data::new(AppState { db: pool.clone() }))
+            .app_data(Data::new(AppState {
+                db: pool.clone(),
+                mailer: mailer.clone(),
+                login_throttle: login_throttle.clone(),
+                keyring: keyring.clone(),
+                oidc: oidc.clone(),
+                check_in_feed: check_in_feed.clone(),
+            }))
             .wrap(cors)
+            // Routes outside /api/v1 are the pre-v1 paths, kept for one more
+            // release. Flag them so clients and logs show who still uses them.
+            .wrap_fn(|req, srv| {
+                let fut = srv.call(req);
+                async move {
+                    let mut res = fut.await?;
+                    if let Some(pattern) = res.request().match_pattern() {
+                        if pattern != "/"
+                            && !pattern.starts_with("/api/v1/")
+                            && !pattern.starts_with("/.well-known/")
+                        {
+                            log::warn!(
+                                "Deprecated route {} {} called, use /api/v1 instead",
+                                res.request().method(),
+                                pattern
+                            );
+                            res.headers_mut().insert(
+                                header::HeaderName::from_static("deprecation"),
+                                HeaderValue::from_static("true"),
+                            );
+                        }
+                    }
+                    Ok(res)
+                }
+            })
             .route("/", web::get().to(greet))
             .service(get_user)
             .service(add_user)
             .service(logout)
             .service(refresh_access_token_handler)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(oidc_authorize)
+            .service(oidc_callback)
+            .service(verify_totp_login)
+            .service(enroll_totp)
+            .service(confirm_totp)
+            .service(disable_totp)
+            .service(set_role_mfa_policy)
             .service(delete_user)
+            .service(update_user_role)
+            .service(verify_email)
+            .service(resend_verification)
+            .service(get_sessions)
+            .service(revoke_other_sessions)
+            .service(revoke_user_session)
             .service(generate_ticket)
             .service(get_ticket)
             .service(delete_ticket)
             .service(create_event)
+            // Before get_event, which would take "search" for an event id
+            .service(search_events)
             .service(get_event)
             .service(get_event_by_user)
             .service(get_events)
+            .service(update_event)
+            .service(get_event_changes)
+            .service(publish_event)
+            .service(pause_event_sales)
+            .service(postpone_event)
+            .service(cancel_event)
             .service(delete_event)
+            .service(get_event_members)
+            .service(add_event_member)
+            .service(remove_event_member)
+            .service(create_api_key)
+            .service(get_api_keys)
+            .service(revoke_api_key)
             .service(book_ticket)
             .service(get_bookings)
             .service(ticket_verification)
+            .service(get_admissions)
+            .service(update_admission)
+            .service(get_admission_credential)
+            .service(scan_ticket_credential)
+            .service(get_scan_manifest)
+            .service(sync_scans)
+            .service(get_check_ins)
+            .service(get_check_in_feed)
+            .service(get_entry_policy)
+            .service(set_entry_policy)
             .service(delete_booking)
+            .service(delete_by_id)
+            .service(jwks)
             .wrap(Logger::default())
     })
     .bind("127.0.0.1:8080")?
@@ -1,10 +1,25 @@
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::Postgres, Pool};
+use std::sync::Arc;
 use uuid::Uuid;
+
+use crate::check_in_feed::CheckInFeed;
+use crate::event_listing::EventSort;
+use crate::event_status::EventStatus;
+use crate::keyring::Keyring;
+use crate::login_throttle::LoginThrottle;
+use crate::mailer::Mailer;
+use crate::oidc::OidcClient;
 // Define the application state
 pub struct AppState {
     pub db: Pool<Postgres>,
+    pub mailer: Arc<dyn Mailer>,
+    pub login_throttle: Arc<LoginThrottle>,
+    pub keyring: Arc<Keyring>,
+    pub oidc: Arc<OidcClient>,
+    pub check_in_feed: Arc<CheckInFeed>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -12,22 +27,35 @@ pub struct User {
     pub user_id: Uuid,
     pub username: String,
     pub email: String,
-    pub password: String,
+    // None for accounts created through social login
+    pub password: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub phone_number: Option<String>,
     pub registration_date: Option<NaiveDateTime>,
+    pub role: String,
+    pub email_verified_at: Option<NaiveDateTime>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Event {
     pub event_id: Uuid,
     pub user_id: Option<Uuid>,
     pub event_name: String,
+    // Local date the event starts on
     pub event_date: NaiveDate,
     pub event_location: Option<String>,
     pub event_description: Option<String>,
-    pub event_status: bool,
+    // See EventStatus
+    pub event_status: String,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub doors_open_at: Option<DateTime<Utc>>,
+    // IANA name, e.g. "Asia/Kolkata"
+    pub timezone: String,
+    // Free-form, such as "music" or "workshop"
+    pub category: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Ticket {
@@ -36,7 +64,8 @@ pub struct Ticket {
     pub event_id: Option<Uuid>,
     pub ticket_type: Option<String>,
     pub availability: Option<i32>,
-    pub price: String,
+    pub price: Decimal,
+    pub currency: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -45,10 +74,109 @@ pub struct Booking {
     pub user_id: Option<Uuid>,
     pub event_name: Option<String>,
     pub ticket_id: Option<Uuid>,
-    pub quantity: String,
-    pub total_price: String,
+    pub quantity: i32,
+    pub total_price: Decimal,
     pub booking_date: Option<NaiveDateTime>,
+    // Every admission on the booking is checked in
     pub verified: bool,
+    pub currency: String,
+    // Admissions checked in so far
+    pub checked_in: i64,
+}
+
+// One person's entry on a booking
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Admission {
+    pub admission_id: Uuid,
+    pub booking_id: Uuid,
+    pub seq: i32,
+    pub holder_name: Option<String>,
+    // Has entered at least once
+    pub verified: bool,
+    // Time of the first entry
+    pub checked_in_at: Option<NaiveDateTime>,
+    // Scanner device that checked the admission in; None when done online
+    pub checked_in_device: Option<String>,
+    pub inside: bool,
+    pub entries: i32,
+}
+
+// One scan at the door, as kept in the event's scan history
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CheckIn {
+    pub check_in_id: Uuid,
+    pub event_id: Uuid,
+    pub booking_id: Uuid,
+    pub admission_id: Option<Uuid>,
+    // "in" or "out"
+    pub direction: String,
+    pub accepted: bool,
+    pub reason: Option<String>,
+    pub gate: Option<String>,
+    pub scanned_by: Option<Uuid>,
+    pub api_key_id: Option<Uuid>,
+    pub device_id: Option<String>,
+    pub scanned_at: NaiveDateTime,
+    pub recorded_at: NaiveDateTime,
+    // Order scans were recorded in, used to resume the live check-in feed
+    pub seq: i64,
+}
+
+// Where a dashboard picks the live feed back up. Browsers resend the last id
+// in the Last-Event-ID header; this is for clients starting from a saved one.
+#[derive(Debug, Deserialize)]
+pub struct FeedQuery {
+    pub last_event_id: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EntryPolicy {
+    pub reentry_allowed: bool,
+    // No limit when left out
+    pub max_entries: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EventMember {
+    pub event_id: Uuid,
+    pub user_id: Uuid,
+    pub role: String,
+    pub added_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+// Listing view of an API key; the hash never leaves the database
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub key_id: Uuid,
+    pub event_id: Uuid,
+    pub created_by: Option<Uuid>,
+    pub name: String,
+    pub prefix: String,
+    pub permissions: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewApiKey {
+    pub name: String,
+    // e.g. ["verify"]
+    pub permissions: Vec<String>,
+    // Never expires when left out
+    pub expires_in_days: Option<i64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -61,9 +189,46 @@ pub struct Login {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NewEvent {
     pub event_name: String,
-    pub event_date: String,
+    // Either a date for an all-day event, or starts_at and ends_at. Times
+    // without an offset are local to the timezone, which defaults to UTC.
+    pub event_date: Option<String>,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub doors_open_at: Option<String>,
+    pub timezone: Option<String>,
     pub event_location: String,
     pub event_description: String,
+    pub category: Option<String>,
+}
+
+// Fields of an event to change. Those left out stay as they are.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventUpdate {
+    pub event_name: Option<String>,
+    pub event_date: Option<String>,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    // An empty string clears it
+    pub doors_open_at: Option<String>,
+    pub timezone: Option<String>,
+    pub event_location: Option<String>,
+    pub event_description: Option<String>,
+    // An empty string clears it
+    pub category: Option<String>,
+}
+
+// One field changed by an edit to an event
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EventChange {
+    pub change_id: Uuid,
+    pub update_id: Uuid,
+    pub event_id: Uuid,
+    pub changed_by: Option<Uuid>,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub changed_at: NaiveDateTime,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -72,16 +237,20 @@ pub struct NewTicket {
     pub email: String,
     pub event_name: String,
     pub ticket_type: String,
-    pub price: String,
+    pub price: Decimal,
+    // ISO 4217 code, defaults to INR
+    pub currency: Option<String>,
     pub availability: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NewBooking {
-    pub event_name: String,
     pub ticket_id: Uuid,
     pub quantity: String,
-    pub price: String,
+    // Price the client was shown; only used to detect a stale quote
+    pub price: Option<Decimal>,
+    // Name for each admission in order; the rest can be filled in later
+    pub holder_names: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -94,6 +263,141 @@ pub struct NewUser {
     pub phone_number: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewEventMember {
+    pub email: String,
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailVerification {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordForgot {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpCode {
+    // A 6-digit authenticator code, or a recovery code where accepted
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MfaChallenge {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MfaPolicy {
+    pub required: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdmissionUpdate {
+    pub holder_name: String,
+}
+
+// A page of a listing. `cursor` is the next_cursor of the page before.
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+// Filters and sort for the event catalog, on top of a page
+#[derive(Debug, Deserialize)]
+pub struct EventQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    // Words to search names, venues and descriptions for
+    pub q: Option<String>,
+    // Defaults to relevance when searching, otherwise date
+    pub sort: Option<EventSort>,
+    // Local dates the event starts between, both included
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    // Part of the venue, in any case
+    pub location: Option<String>,
+    pub status: Option<EventStatus>,
+    // The event's owner
+    pub organizer: Option<Uuid>,
+    pub category: Option<String>,
+    // Has a ticket priced within the range
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    // true keeps only events with a ticket in that range still available
+    pub available: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CredentialQuery {
+    // "svg" (default), "png", or "jws" for the bare signed token
+    pub format: Option<String>,
+}
+
+// Which way a scanned person is going through the gate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    In,
+    Out,
+}
+
+// Where and how a scan was made; all optional so older scanner apps keep working
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ScanContext {
+    pub gate: Option<String>,
+    pub direction: Option<Direction>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TicketScan {
+    // Signed ticket credential read from the attendee's QR code
+    pub credential: String,
+    #[serde(flatten)]
+    pub context: ScanContext,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OfflineScan {
+    pub credential: String,
+    // Device clock when the credential was scanned
+    pub scanned_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScanUpload {
+    // Stable identifier the scanner device picks for itself
+    pub device_id: String,
+    pub gate: Option<String>,
+    // The scan manifest the device checked the scans against
+    pub manifest_id: Uuid,
+    pub scans: Vec<OfflineScan>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoleUpdate {
+    pub role: String,
+}
+
 // CREATE TABLE Users (
 //     user_id SERIAL PRIMARY KEY,
 //     username VARCHAR(255) NOT NULL,
@@ -0,0 +1,60 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_CURRENCY: &str = "INR";
+
+// Every amount is kept to two decimal places (paise/cents)
+const SCALE: u32 = 2;
+
+/// An amount in a single ISO 4217 currency.
+///
+/// In JSON the amount is always a string with two decimal places so clients
+/// never round-trip money through floats:
+/// `{ "amount": "1499.50", "currency": "INR" }`.
+/// Ticket and booking rows use the same encoding for their flat
+/// `price` / `total_price` fields next to a `currency` field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Money {
+    pub amount: Decimal,
+    pub currency: String,
+}
+
+impl Money {
+    pub fn new(amount: Decimal, currency: &str) -> Result<Self, String> {
+        if amount.is_sign_negative() {
+            return Err("Amount cannot be negative".to_string());
+        }
+        if amount.normalize().scale() > SCALE {
+            return Err(format!(
+                "Amount cannot have more than {} decimal places",
+                SCALE
+            ));
+        }
+        let mut amount = amount;
+        amount.rescale(SCALE);
+        Ok(Money {
+            amount,
+            currency: parse_currency(currency)?,
+        })
+    }
+
+    /// Price of `quantity` units, or `None` if the result overflows.
+    pub fn times(&self, quantity: i32) -> Option<Self> {
+        let mut amount = self.amount.checked_mul(Decimal::from(quantity))?;
+        amount.rescale(SCALE);
+        Some(Money {
+            amount,
+            currency: self.currency.clone(),
+        })
+    }
+}
+
+/// Normalises a currency code to upper case and checks it looks like ISO 4217.
+pub fn parse_currency(code: &str) -> Result<String, String> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(code)
+    } else {
+        Err(format!("Invalid currency code: {}", code))
+    }
+}
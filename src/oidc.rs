@@ -0,0 +1,452 @@
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie, SameSite};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use core::fmt;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::Postgres, Pool};
+use std::collections::HashMap;
+use std::env;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+use crate::models::User;
+use crate::refresh_token::hash_token;
+use crate::session::revoke_user_sessions;
+
+const STATE_MINUTES: i64 = 10;
+// Holds the hash of the state of the login the browser started
+pub const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Debug)]
+pub enum OidcError {
+    UnknownProvider,
+    // State unknown, expired or already used
+    InvalidState,
+    // The provider didn't vouch for an email address we could link
+    UnverifiedEmail,
+    Provider(String),
+    Token(jsonwebtoken::errors::Error),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::UnknownProvider => write!(f, "Unknown login provider"),
+            OidcError::InvalidState => write!(f, "Login attempt expired, please try again"),
+            OidcError::UnverifiedEmail => {
+                write!(f, "The provider did not confirm a verified email address")
+            }
+            OidcError::Provider(err) => write!(f, "Login provider error: {}", err),
+            OidcError::Token(err) => write!(f, "Invalid ID token: {}", err),
+            OidcError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for OidcError {
+    fn from(err: sqlx::Error) -> Self {
+        OidcError::Database(err)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for OidcError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        OidcError::Token(err)
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        OidcError::Provider(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+    pub redirect_uri: String,
+}
+
+// The parts of the provider's discovery document we use
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdentityClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+pub struct OidcClient {
+    providers: HashMap<String, OidcProvider>,
+    http: reqwest::Client,
+    // Discovery documents, fetched on first use
+    metadata: RwLock<HashMap<String, ProviderMetadata>>,
+}
+
+impl OidcClient {
+    /// Reads the comma-separated provider names in OIDC_PROVIDERS. Each name
+    /// is configured with OIDC_<NAME>_ISSUER and OIDC_<NAME>_CLIENT_ID, plus
+    /// optional OIDC_<NAME>_CLIENT_SECRET, OIDC_<NAME>_SCOPES (default
+    /// `openid email profile`) and OIDC_<NAME>_REDIRECT_URI (default
+    /// `{APP_URL}/oidc/<name>/callback`).
+    pub fn from_env() -> Result<Self, String> {
+        let app_url = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+        let mut providers = Vec::new();
+        let names = env::var("OIDC_PROVIDERS").unwrap_or_default();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let var = |key: &str| env::var(format!("OIDC_{}_{}", name.to_uppercase(), key)).ok();
+            let required = |key: &str| {
+                var(key).ok_or_else(|| format!("OIDC_{}_{} is not set", name.to_uppercase(), key))
+            };
+            let provider = OidcProvider {
+                name: name.to_string(),
+                issuer: required("ISSUER")?.trim_end_matches('/').to_string(),
+                client_id: required("CLIENT_ID")?,
+                client_secret: var("CLIENT_SECRET"),
+                scopes: var("SCOPES").unwrap_or_else(|| "openid email profile".to_string()),
+                redirect_uri: var("REDIRECT_URI")
+                    .unwrap_or_else(|| format!("{}/oidc/{}/callback", app_url, name)),
+            };
+            providers.push(provider);
+        }
+        Ok(OidcClient::new(providers))
+    }
+
+    pub fn new(providers: Vec<OidcProvider>) -> Self {
+        OidcClient {
+            providers: providers
+                .into_iter()
+                .map(|provider| (provider.name.clone(), provider))
+                .collect(),
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn provider(&self, name: &str) -> Result<&OidcProvider, OidcError> {
+        self.providers.get(name).ok_or(OidcError::UnknownProvider)
+    }
+
+    async fn metadata(&self, provider: &OidcProvider) -> Result<ProviderMetadata, OidcError> {
+        if let Some(metadata) = self.metadata.read().unwrap().get(&provider.name) {
+            return Ok(metadata.clone());
+        }
+        let metadata: ProviderMetadata = self
+            .http
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                provider.issuer
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer.trim_end_matches('/') != provider.issuer {
+            return Err(OidcError::Provider(
+                "discovery document is for a different issuer".to_string(),
+            ));
+        }
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(provider.name.clone(), metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Starts an authorization-code login with PKCE, returning the URL to send
+    /// the browser to and the state the login must come back with.
+    pub async fn authorization_url(
+        &self,
+        db: &Pool<Postgres>,
+        provider_name: &str,
+    ) -> Result<AuthorizationRequest, OidcError> {
+        let provider = self.provider(provider_name)?;
+        let metadata = self.metadata(provider).await?;
+
+        let state = random_token();
+        let nonce = random_token();
+        let code_verifier = random_token();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let expires_at =
+            (chrono::Utc::now() + chrono::Duration::minutes(STATE_MINUTES)).naive_utc();
+        sqlx::query!(
+            "INSERT INTO oidc_login_states (state_hash, provider, code_verifier, nonce, expires_at)
+            VALUES ($1, $2, $3, $4, $5)",
+            hash_token(&state),
+            provider.name,
+            code_verifier,
+            nonce,
+            expires_at
+        )
+        .execute(db)
+        .await?;
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", provider.redirect_uri.as_str()),
+                ("scope", provider.scopes.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|err| OidcError::Provider(err.to_string()))?;
+        Ok(AuthorizationRequest {
+            url: url.into(),
+            state,
+        })
+    }
+
+    /// Finishes a login: redeems the state, exchanges the code and returns the
+    /// verified claims of the ID token. `state_cookie` is the STATE_COOKIE the
+    /// browser sent, so a login can't be finished in another browser.
+    pub async fn exchange_code(
+        &self,
+        db: &Pool<Postgres>,
+        provider_name: &str,
+        code: &str,
+        state: &str,
+        state_cookie: Option<&str>,
+    ) -> Result<IdentityClaims, OidcError> {
+        let provider = self.provider(provider_name)?;
+        if state_cookie != Some(hash_token(state).as_str()) {
+            return Err(OidcError::InvalidState);
+        }
+        // Each state is good for one attempt
+        let pending = sqlx::query!(
+            "DELETE FROM oidc_login_states
+            WHERE state_hash = $1 AND provider = $2 AND expires_at > NOW()
+            RETURNING code_verifier, nonce",
+            hash_token(state),
+            provider.name
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or(OidcError::InvalidState)?;
+
+        let metadata = self.metadata(provider).await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", pending.code_verifier.as_str()),
+        ];
+        if let Some(secret) = &provider.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Provider(format!(
+                "token request failed: {}",
+                body
+            )));
+        }
+        let tokens: TokenResponse = response.json().await?;
+
+        let header = decode_header(&tokens.id_token)?;
+        // Only accept asymmetric signatures we can check against the provider's JWKS
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(OidcError::Token(ErrorKind::InvalidAlgorithm.into()));
+        }
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or_else(|| OidcError::Provider("ID token signed with an unknown key".to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+        let claims =
+            decode::<IdentityClaims>(&tokens.id_token, &DecodingKey::from_jwk(jwk)?, &validation)?
+                .claims;
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(OidcError::InvalidState);
+        }
+        Ok(claims)
+    }
+}
+
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+}
+
+/// Binds a login to the browser that started it, for the callback to check.
+pub fn state_cookie(state: &str) -> Cookie<'static> {
+    let mut cookie = base_state_cookie(hash_token(state));
+    cookie.set_max_age(CookieDuration::minutes(STATE_MINUTES));
+    cookie
+}
+
+/// Clears the state cookie. Browsers only replace a cookie set with the same
+/// name and path.
+pub fn removal_state_cookie() -> Cookie<'static> {
+    let mut cookie = base_state_cookie(String::new());
+    cookie.make_removal();
+    cookie
+}
+
+fn base_state_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, value)
+        .path("/api/v1/auth/oidc")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Finds the user behind an external identity. Unknown identities are linked
+/// to the account with the same verified email, or get a new account without
+/// a password. An account whose email was never verified loses its password
+/// and sessions when it is linked.
+pub async fn find_or_create_user(
+    db: &Pool<Postgres>,
+    provider: &str,
+    claims: &IdentityClaims,
+) -> Result<User, OidcError> {
+    let mut tx = db.begin().await?;
+    let linked = sqlx::query_as!(
+        User,
+        "SELECT u.* FROM users u
+        JOIN user_identities i ON i.user_id = u.user_id
+        WHERE i.provider = $1 AND i.subject = $2",
+        provider,
+        claims.sub
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(user) = linked {
+        sqlx::query!(
+            "UPDATE user_identities SET last_login_at = NOW() WHERE provider = $1 AND subject = $2",
+            provider,
+            claims.sub
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(user);
+    }
+
+    // Linking by an address the provider hasn't verified would let anyone
+    // take over the account registered under it
+    let email = match &claims.email {
+        Some(email) if claims.email_verified => email,
+        _ => return Err(OidcError::UnverifiedEmail),
+    };
+    let existing = sqlx::query_as!(
+        User,
+        "SELECT * FROM users WHERE email = $1 FOR UPDATE",
+        email
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let user = match existing {
+        Some(user) if user.email_verified_at.is_some() => user,
+        // Whoever registered the address without confirming it may not own
+        // it, so the provider's user takes the account over from them
+        Some(user) => {
+            revoke_user_sessions(&mut *tx, user.user_id, None).await?;
+            sqlx::query_as!(
+                User,
+                "UPDATE users SET password = NULL, email_verified_at = NOW()
+                WHERE user_id = $1
+                RETURNING *",
+                user.user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+        None => {
+            let username = claims
+                .preferred_username
+                .clone()
+                .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+            sqlx::query_as!(
+                User,
+                "INSERT INTO users (user_id, username, email, password, first_name, last_name, email_verified_at)
+                VALUES ($1, $2, $3, NULL, $4, $5, NOW())
+                RETURNING *",
+                Uuid::new_v4(),
+                username,
+                email,
+                claims.given_name,
+                claims.family_name
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+
+    sqlx::query!(
+        "INSERT INTO user_identities (provider, subject, user_id, email) VALUES ($1, $2, $3, $4)",
+        provider,
+        claims.sub,
+        user.user_id,
+        email
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(user)
+}
@@ -0,0 +1,97 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use core::fmt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageError {
+    BadCursor,
+    BadLimit,
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::BadCursor => write!(
+                f,
+                "cursor must be a next_cursor from a listing with the same sort"
+            ),
+            PageError::BadLimit => write!(f, "limit must be between 1 and {}", MAX_PAGE_SIZE),
+        }
+    }
+}
+
+/// The last row of a page: its sort key, with the id to break ties. The
+/// next page starts right after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cursor<K> {
+    pub key: K,
+    pub id: Uuid,
+}
+
+// Handed to clients as an opaque string
+impl<K: Serialize> Cursor<K> {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serializes");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+}
+
+impl<K: DeserializeOwned> Cursor<K> {
+    fn decode(cursor: &str) -> Result<Self, PageError> {
+        let json = URL_SAFE_NO_PAD
+            .decode(cursor.trim())
+            .map_err(|_| PageError::BadCursor)?;
+        serde_json::from_slice(&json).map_err(|_| PageError::BadCursor)
+    }
+}
+
+/// Which page of a listing to fetch
+#[derive(Debug)]
+pub struct Page<K> {
+    pub after: Option<Cursor<K>>,
+    pub limit: i64,
+}
+
+impl<K: Serialize + DeserializeOwned> Page<K> {
+    pub fn new(cursor: Option<&str>, limit: Option<i64>) -> Result<Self, PageError> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(PageError::BadLimit);
+        }
+        let after = cursor.map(Cursor::decode).transpose()?;
+        Ok(Page { after, limit })
+    }
+
+    pub fn after_key(&self) -> Option<&K> {
+        self.after.as_ref().map(|cursor| &cursor.key)
+    }
+
+    pub fn after_id(&self) -> Option<Uuid> {
+        self.after.as_ref().map(|cursor| cursor.id)
+    }
+
+    // One row more than the page holds, to tell whether another page follows
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Cuts rows fetched with `fetch_limit` down to the page, returning them
+    /// with the cursor for the next page if there is one.
+    pub fn finish<T>(
+        &self,
+        mut rows: Vec<T>,
+        cursor_of: impl Fn(&T) -> Cursor<K>,
+    ) -> (Vec<T>, Option<String>) {
+        if rows.len() as i64 <= self.limit {
+            return (rows, None);
+        }
+        rows.truncate(self.limit as usize);
+        let next_cursor = rows.last().map(|last| cursor_of(last).encode());
+        (rows, next_cursor)
+    }
+}
@@ -0,0 +1,93 @@
+use rand::RngCore;
+use sqlx::{postgres::Postgres, Pool};
+use std::env;
+
+use crate::mailer::{Email, Mailer};
+use crate::refresh_token::hash_token;
+use crate::session::revoke_user_sessions;
+
+const TOKEN_MINUTES: i64 = 60;
+
+/// Emails a single-use reset link to the account registered under `email`.
+/// Does nothing if there is no such account.
+pub async fn request_password_reset(
+    db: &Pool<Postgres>,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> Result<(), String> {
+    let user_id = sqlx::query_scalar!("SELECT user_id FROM users WHERE email = $1", email)
+        .fetch_optional(db)
+        .await
+        .map_err(|err| err.to_string())?;
+    let Some(user_id) = user_id else {
+        return Ok(());
+    };
+
+    // Only the hash is stored, so a database leak does not expose live tokens
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let expires_at = (chrono::Utc::now() + chrono::Duration::minutes(TOKEN_MINUTES)).naive_utc();
+    sqlx::query!(
+        "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        hash_token(&token),
+        user_id,
+        expires_at
+    )
+    .execute(db)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    let app_url = env::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Reset your KriyaPass password".to_string(),
+            body: format!(
+                "Choose a new password by opening this link within {} minutes:\n{}/reset-password?token={}\n\nIf you did not ask for this, you can ignore this email.",
+                TOKEN_MINUTES, app_url, token
+            ),
+        })
+        .await
+}
+
+/// Redeems a reset token, replacing the user's password with `password_hash`
+/// and signing them out everywhere. Returns false if the token is invalid,
+/// expired or already used.
+pub async fn reset_password(
+    db: &Pool<Postgres>,
+    token: &str,
+    password_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM password_resets
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        FOR UPDATE",
+        hash_token(token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(user_id) = user_id else {
+        return Ok(false);
+    };
+
+    sqlx::query!(
+        "UPDATE users SET password = $1 WHERE user_id = $2",
+        password_hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // Burn this token along with any others still outstanding for the user
+    sqlx::query!(
+        "UPDATE password_resets SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    revoke_user_sessions(&mut *tx, user_id, None).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use core::fmt;
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::Postgres, Pool};
+use uuid::Uuid;
+
+use crate::keyring::{Keyring, TokenKind};
+use crate::session::revoke_session;
+use crate::token::{generate_jwt_token, verify_jwt_token, TokenDetails};
+
+#[derive(Debug)]
+pub enum RefreshError {
+    // Bad signature, expired, unknown or revoked
+    Invalid,
+    // An already rotated token was presented again; its session is now revoked
+    Reused,
+    Token(jsonwebtoken::errors::Error),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Invalid => write!(f, "Invalid or revoked refresh token"),
+            RefreshError::Reused => write!(f, "Refresh token reuse detected"),
+            RefreshError::Token(err) => write!(f, "{}", err),
+            RefreshError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(err: sqlx::Error) -> Self {
+        RefreshError::Database(err)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for RefreshError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        RefreshError::Token(err)
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Signs a refresh token for `user_id` and records its hash. The session id
+/// is used as the token's family.
+pub async fn issue_refresh_token<'e, E>(
+    executor: E,
+    keyring: &Keyring,
+    user_id: Uuid,
+    session_id: Uuid,
+    days: i64,
+) -> Result<TokenDetails, RefreshError>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    let token = generate_jwt_token(keyring, TokenKind::Refresh, user_id, session_id, None, days)?;
+    let expires_at = DateTime::<Utc>::from_timestamp(token.expires_in.unwrap_or_default(), 0)
+        .unwrap_or_default()
+        .naive_utc();
+
+    sqlx::query!(
+        "INSERT INTO refresh_tokens (token_id, user_id, family_id, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4, $5)",
+        Uuid::new_v4(),
+        user_id,
+        session_id,
+        hash_token(token.token.as_deref().unwrap_or_default()),
+        expires_at
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(token)
+}
+
+/// Exchanges a refresh token for a new one in the same session. The old token
+/// is marked used; presenting it again revokes the whole session.
+pub async fn rotate_refresh_token(
+    db: &Pool<Postgres>,
+    keyring: &Keyring,
+    presented: &str,
+) -> Result<TokenDetails, RefreshError> {
+    if verify_jwt_token(keyring, TokenKind::Refresh, presented).is_err() {
+        return Err(RefreshError::Invalid);
+    }
+
+    let mut tx = db.begin().await?;
+    let stored = sqlx::query!(
+        "SELECT r.token_id, r.user_id, r.family_id, r.used_at, r.revoked_at
+        FROM refresh_tokens r
+        JOIN sessions s ON s.session_id = r.family_id
+        WHERE r.token_hash = $1 AND r.expires_at > NOW() AND s.revoked_at IS NULL
+        FOR UPDATE OF r",
+        hash_token(presented)
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let stored = match stored {
+        Some(stored) if stored.revoked_at.is_none() => stored,
+        _ => return Err(RefreshError::Invalid),
+    };
+
+    if stored.used_at.is_some() {
+        revoke_session(&mut *tx, stored.family_id).await?;
+        tx.commit().await?;
+        return Err(RefreshError::Reused);
+    }
+
+    sqlx::query!(
+        "UPDATE refresh_tokens SET used_at = NOW() WHERE token_id = $1",
+        stored.token_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE sessions SET last_used_at = NOW() WHERE session_id = $1",
+        stored.family_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let token = issue_refresh_token(&mut *tx, keyring, stored.user_id, stored.family_id, 7).await?;
+    tx.commit().await?;
+
+    Ok(token)
+}
+
+/// Revokes the session the presented token belongs to, ending that login.
+pub async fn revoke_refresh_token(db: &Pool<Postgres>, presented: &str) -> Result<(), sqlx::Error> {
+    let session_id = sqlx::query_scalar!(
+        "SELECT family_id FROM refresh_tokens WHERE token_hash = $1",
+        hash_token(presented)
+    )
+    .fetch_optional(db)
+    .await?;
+    match session_id {
+        Some(session_id) => revoke_session(db, session_id).await,
+        None => Ok(()),
+    }
+}
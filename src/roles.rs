@@ -0,0 +1,201 @@
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError};
+use actix_web::{dev::Payload, Error as ActixWebError};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::Postgres, Pool};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+use crate::jwt_auth::{ErrorResponse, JwtMiddleware};
+use crate::models::{AppState, User};
+use crate::totp::enrollment_pending;
+
+// Account-wide role stored in users.role
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Attendee,
+    Organizer,
+    Scanner,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Attendee => "attendee",
+            Role::Organizer => "organizer",
+            Role::Scanner => "scanner",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "attendee" => Some(Role::Attendee),
+            "organizer" => Some(Role::Organizer),
+            "scanner" => Some(Role::Scanner),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    // Admins pass every role check
+    pub fn allows(&self, required: Role) -> bool {
+        *self == Role::Admin || *self == required
+    }
+}
+
+impl User {
+    pub fn account_role(&self) -> Role {
+        Role::parse(&self.role).unwrap_or(Role::Attendee)
+    }
+}
+
+// Role a user holds on one event, stored in event_members.role
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventRole {
+    Owner,
+    CoOrganizer,
+    Scanner,
+}
+
+impl EventRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventRole::Owner => "owner",
+            EventRole::CoOrganizer => "co_organizer",
+            EventRole::Scanner => "scanner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "owner" => Some(EventRole::Owner),
+            "co_organizer" => Some(EventRole::CoOrganizer),
+            "scanner" => Some(EventRole::Scanner),
+            _ => None,
+        }
+    }
+}
+
+// Roles allowed to manage an event's tickets and bookings
+pub const ORGANIZERS: &[EventRole] = &[EventRole::Owner, EventRole::CoOrganizer];
+// Roles allowed to check attendees in at the door
+pub const DOOR_STAFF: &[EventRole] =
+    &[EventRole::Owner, EventRole::CoOrganizer, EventRole::Scanner];
+
+pub trait RoleMarker {
+    const ROLE: Role;
+}
+
+pub struct Organizer;
+pub struct Admin;
+
+impl RoleMarker for Organizer {
+    const ROLE: Role = Role::Organizer;
+}
+
+impl RoleMarker for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+// Extractor that authenticates like JwtMiddleware and then requires an account role
+pub struct RequireRole<R: RoleMarker> {
+    pub user: User,
+    _role: PhantomData<R>,
+}
+
+impl<R: RoleMarker> FromRequest for RequireRole<R> {
+    type Error = ActixWebError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = req.app_data::<web::Data<AppState>>().unwrap().clone();
+        let jwt_guard = JwtMiddleware::from_request(req, payload);
+        Box::pin(async move {
+            let user = jwt_guard.await?.user;
+
+            if !user.account_role().allows(R::ROLE) {
+                let json_error = ErrorResponse {
+                    status: "fail".to_string(),
+                    message: format!("This action requires the {} role", R::ROLE.as_str()),
+                };
+                return Err(ErrorForbidden(json_error));
+            }
+            if enrollment_pending(&data.db, &user)
+                .await
+                .map_err(ErrorInternalServerError)?
+            {
+                let json_error = ErrorResponse {
+                    status: "fail".to_string(),
+                    message: "Set up two-factor authentication to continue".to_string(),
+                };
+                return Err(ErrorForbidden(json_error));
+            }
+
+            Ok(RequireRole {
+                user,
+                _role: PhantomData,
+            })
+        })
+    }
+}
+
+pub fn forbidden(message: &str) -> HttpResponse {
+    HttpResponse::Forbidden().json(json!({
+        "status": "fail",
+        "message": message
+    }))
+}
+
+/// Checks that `user` holds one of `allowed` on the event, returning the
+/// response to send back when they don't. Admins always pass, provided they
+/// have set up 2FA where their role requires it.
+pub async fn require_event_role(
+    db: &Pool<Postgres>,
+    user: &User,
+    event_id: Uuid,
+    allowed: &[EventRole],
+) -> Result<(), HttpResponse> {
+    match enrollment_pending(db, user).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return Err(HttpResponse::Forbidden().json(json!({
+                "status": "fail",
+                "code": "mfa_enrollment_required",
+                "message": "Set up two-factor authentication to continue"
+            })))
+        }
+        Err(err) => {
+            return Err(HttpResponse::InternalServerError().json(json!({
+                "status": "fail",
+                "error": err.to_string()
+            })))
+        }
+    }
+    if user.account_role() == Role::Admin {
+        return Ok(());
+    }
+
+    let membership = sqlx::query_scalar!(
+        "SELECT role FROM event_members WHERE event_id = $1 AND user_id = $2",
+        event_id,
+        user.user_id
+    )
+    .fetch_optional(db)
+    .await;
+
+    match membership {
+        Ok(Some(role)) if EventRole::parse(&role).is_some_and(|role| allowed.contains(&role)) => {
+            Ok(())
+        }
+        Ok(_) => Err(forbidden("You do not have access to this event")),
+        Err(err) => Err(HttpResponse::InternalServerError().json(json!({
+            "status": "fail",
+            "error": err.to_string()
+        }))),
+    }
+}
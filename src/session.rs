@@ -0,0 +1,75 @@
+use actix_web::{http::header, HttpRequest};
+use sqlx::{postgres::Postgres, Pool};
+use uuid::Uuid;
+
+/// Records a new login for `user_id`, capturing the device it came from.
+pub async fn create_session(
+    db: &Pool<Postgres>,
+    user_id: Uuid,
+    req: &HttpRequest,
+) -> Result<Uuid, sqlx::Error> {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let ip_address = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(|addr| addr.to_string());
+
+    sqlx::query_scalar!(
+        "INSERT INTO sessions (session_id, user_id, user_agent, ip_address)
+        VALUES ($1, $2, $3, $4)
+        RETURNING session_id",
+        Uuid::new_v4(),
+        user_id,
+        user_agent,
+        ip_address
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Revokes a session together with every refresh token issued for it.
+pub async fn revoke_session<'e, E>(executor: E, session_id: Uuid) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    sqlx::query!(
+        "WITH revoked AS (
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE session_id = $1 AND revoked_at IS NULL
+        )
+        UPDATE refresh_tokens SET revoked_at = NOW()
+        WHERE family_id = $1 AND revoked_at IS NULL",
+        session_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Revokes every session of `user_id` except `keep`, along with their refresh tokens.
+pub async fn revoke_user_sessions<'e, E>(
+    executor: E,
+    user_id: Uuid,
+    keep: Option<Uuid>,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Postgres>,
+{
+    sqlx::query!(
+        "WITH revoked AS (
+            UPDATE sessions SET revoked_at = NOW()
+            WHERE user_id = $1 AND session_id IS DISTINCT FROM $2 AND revoked_at IS NULL
+        )
+        UPDATE refresh_tokens SET revoked_at = NOW()
+        WHERE user_id = $1 AND family_id IS DISTINCT FROM $2 AND revoked_at IS NULL",
+        user_id,
+        keep
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
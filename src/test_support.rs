@@ -0,0 +1,149 @@
+// Setup shared by the tests that run against the database in DATABASE_URL.
+// Each test makes its own users and events under fresh ids, so tests can run
+// side by side, and deletes its users when done, which cascades to the rest.
+use actix_web::web::Data;
+use bcrypt::hash;
+use chrono::{Duration, Utc};
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+use crate::check_in_feed::CheckInFeed;
+use crate::keyring::{Keyring, TokenKind};
+use crate::login_throttle::{LoginThrottle, MemoryAttemptStore, ThrottleConfig};
+use crate::mailer::LogMailer;
+use crate::models::{AppState, User};
+use crate::oidc::OidcClient;
+use crate::roles::{EventRole, Role};
+use crate::token::generate_jwt_token;
+
+pub const PASSWORD: &str = "correct horse";
+
+pub async fn test_state() -> Data<AppState> {
+    test_state_with_oidc(OidcClient::from_env().unwrap()).await
+}
+
+pub async fn test_state_with_oidc(oidc: OidcClient) -> Data<AppState> {
+    let db = PgPoolOptions::new()
+        .max_connections(20)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL must be set for tests"))
+        .await
+        .expect("Failed to connect to the test database");
+    Data::new(AppState {
+        db,
+        mailer: Arc::new(LogMailer),
+        login_throttle: Arc::new(LoginThrottle::new(
+            ThrottleConfig::default(),
+            Box::<MemoryAttemptStore>::default(),
+        )),
+        keyring: Arc::new(Keyring::for_tests()),
+        oidc: Arc::new(oidc),
+        check_in_feed: Arc::new(CheckInFeed::default()),
+    })
+}
+
+// Hashed once at the lowest cost, as bcrypt is slow in debug builds
+fn password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash(PASSWORD, 4).unwrap())
+}
+
+/// A user with a verified email and the password PASSWORD.
+pub async fn create_user(state: &AppState, role: Role) -> User {
+    let user_id = Uuid::new_v4();
+    sqlx::query_as!(
+        User,
+        "INSERT INTO users (user_id, username, email, password, role, email_verified_at)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        RETURNING *",
+        user_id,
+        format!("test-{}", user_id),
+        format!("test-{}@example.com", user_id),
+        password_hash(),
+        role.as_str()
+    )
+    .fetch_one(&state.db)
+    .await
+    .unwrap()
+}
+
+pub async fn create_session(state: &AppState, user: &User) -> Uuid {
+    sqlx::query_scalar!(
+        "INSERT INTO sessions (session_id, user_id) VALUES ($1, $2) RETURNING session_id",
+        Uuid::new_v4(),
+        user.user_id
+    )
+    .fetch_one(&state.db)
+    .await
+    .unwrap()
+}
+
+/// Opens a session for `user` and returns an access token for it.
+pub async fn access_token(state: &AppState, user: &User) -> String {
+    let session_id = create_session(state, user).await;
+    generate_jwt_token(
+        &state.keyring,
+        TokenKind::Access,
+        user.user_id,
+        session_id,
+        Some(&user.role),
+        1,
+    )
+    .unwrap()
+    .token
+    .unwrap()
+}
+
+/// A published event a week from now, owned by `owner`.
+pub async fn create_event(state: &AppState, owner: &User) -> Uuid {
+    let event_id = Uuid::new_v4();
+    let starts_at = Utc::now() + Duration::days(7);
+    sqlx::query!(
+        "INSERT INTO events (event_id, user_id, event_name, event_date, event_status,
+            starts_at, ends_at)
+        VALUES ($1, $2, $3, $4, 'published', $5, $6)",
+        event_id,
+        owner.user_id,
+        format!("Test event {}", event_id),
+        starts_at.date_naive(),
+        starts_at,
+        starts_at + Duration::hours(3)
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT INTO event_members (event_id, user_id, role) VALUES ($1, $2, $3)",
+        event_id,
+        owner.user_id,
+        EventRole::Owner.as_str()
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+    event_id
+}
+
+pub async fn create_ticket(state: &AppState, event_id: Uuid, availability: i32) -> Uuid {
+    let ticket_id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO tickets (ticket_id, event_id, ticket_type, availability, price)
+        VALUES ($1, $2, 'GA', $3, 100)",
+        ticket_id,
+        event_id,
+        availability
+    )
+    .execute(&state.db)
+    .await
+    .unwrap();
+    ticket_id
+}
+
+pub async fn delete_users(state: &AppState, users: &[&User]) {
+    let user_ids: Vec<Uuid> = users.iter().map(|user| user.user_id).collect();
+    sqlx::query!("DELETE FROM users WHERE user_id = ANY($1)", &user_ids[..])
+        .execute(&state.db)
+        .await
+        .unwrap();
+}
@@ -0,0 +1,276 @@
+use chrono::{DateTime, Utc};
+use image::{ImageFormat, Luma};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use uuid::Uuid;
+
+use crate::keyring::{Keyring, TokenKind};
+
+// Claims of a ticket credential. Kept short since the whole token goes into a QR code.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TicketClaims {
+    // Booking holder
+    pub sub: String,
+    pub bid: String,
+    // The admission on the booking this credential lets in
+    pub aid: String,
+    pub eid: String,
+    // Ticket tier booked, and its type ("VIP", "Balcony", ...) as the seat
+    pub tid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seat: Option<String>,
+    pub iat: i64,
+    pub exp: i64,
+    pub iss: String,
+    pub aud: String,
+}
+
+// What a verified credential vouches for
+#[derive(Debug, Clone, Serialize)]
+pub struct TicketCredential {
+    pub booking_id: Uuid,
+    pub admission_id: Uuid,
+    pub event_id: Uuid,
+    pub holder: Uuid,
+    pub ticket_id: Uuid,
+    pub seat: Option<String>,
+}
+
+/// Signs a credential for one admission on a booking. It stays valid until a day after the
+/// event ends, so it can be issued long before the doors open.
+pub fn issue_ticket_credential(
+    keyring: &Keyring,
+    credential: &TicketCredential,
+    event_ends_at: DateTime<Utc>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    // Events ending on the last representable day expire with the event
+    let expires = event_ends_at
+        .checked_add_signed(chrono::Duration::days(1))
+        .unwrap_or(event_ends_at);
+    let claims = TicketClaims {
+        sub: credential.holder.to_string(),
+        bid: credential.booking_id.to_string(),
+        aid: credential.admission_id.to_string(),
+        eid: credential.event_id.to_string(),
+        tid: credential.ticket_id.to_string(),
+        seat: credential.seat.clone(),
+        iat: Utc::now().timestamp(),
+        exp: expires.timestamp(),
+        iss: keyring.issuer.clone(),
+        aud: TokenKind::Ticket.audience().to_string(),
+    };
+
+    let (kid, algorithm, key) = keyring.signing_key(TokenKind::Ticket);
+    let mut header = Header::new(algorithm);
+    header.kid = Some(kid.to_string());
+    encode(&header, &claims, key)
+}
+
+/// Checks a scanned credential's signature, issuer, audience and expiry.
+pub fn verify_ticket_credential(
+    keyring: &Keyring,
+    token: &str,
+) -> Result<TicketCredential, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    let (algorithm, key) = keyring
+        .verification_key(TokenKind::Ticket, header.kid.as_deref())
+        .ok_or(ErrorKind::InvalidKeyFormat)?;
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&keyring.issuer]);
+    validation.set_audience(&[TokenKind::Ticket.audience()]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let claims = decode::<TicketClaims>(token, key, &validation)?.claims;
+    let uuid = |value: &str| Uuid::parse_str(value).map_err(|_| ErrorKind::InvalidToken);
+    Ok(TicketCredential {
+        booking_id: uuid(&claims.bid)?,
+        admission_id: uuid(&claims.aid)?,
+        event_id: uuid(&claims.eid)?,
+        holder: uuid(&claims.sub).map_err(|_| ErrorKind::InvalidSubject)?,
+        ticket_id: uuid(&claims.tid)?,
+        seat: claims.seat,
+    })
+}
+
+// One admission in a scan manifest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub aid: Uuid,
+    pub bid: Uuid,
+    pub sub: Uuid,
+    // Shown to door staff so they can match the person to the ticket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Already checked in when the manifest was issued
+    pub used: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestClaims {
+    // Named by the device when it uploads the scans it made with this manifest
+    jti: String,
+    eid: String,
+    admissions: Vec<ManifestEntry>,
+    iat: i64,
+    exp: i64,
+    iss: String,
+    aud: String,
+}
+
+/// Signs the list of an event's valid admissions, so a scanner can check
+/// credentials against it while offline.
+pub fn issue_scan_manifest(
+    keyring: &Keyring,
+    manifest_id: Uuid,
+    event_id: Uuid,
+    admissions: Vec<ManifestEntry>,
+    issued_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = ManifestClaims {
+        jti: manifest_id.to_string(),
+        eid: event_id.to_string(),
+        admissions,
+        iat: issued_at.timestamp(),
+        exp: expires_at.timestamp(),
+        iss: keyring.issuer.clone(),
+        aud: TokenKind::ScanManifest.audience().to_string(),
+    };
+
+    let (kid, algorithm, key) = keyring.signing_key(TokenKind::ScanManifest);
+    let mut header = Header::new(algorithm);
+    header.kid = Some(kid.to_string());
+    encode(&header, &claims, key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+/// Renders a credential as a QR code image, returning its content type and bytes.
+pub fn render_qr(token: &str, format: QrFormat) -> Result<(&'static str, Vec<u8>), String> {
+    let code = QrCode::new(token.as_bytes()).map_err(|err| err.to_string())?;
+    match format {
+        QrFormat::Svg => {
+            let image = code.render::<svg::Color>().min_dimensions(256, 256).build();
+            Ok(("image/svg+xml", image.into_bytes()))
+        }
+        QrFormat::Png => {
+            let image = code.render::<Luma<u8>>().min_dimensions(256, 256).build();
+            let mut bytes = Cursor::new(Vec::new());
+            image
+                .write_to(&mut bytes, ImageFormat::Png)
+                .map_err(|err| err.to_string())?;
+            Ok(("image/png", bytes.into_inner()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{generate_jwt_token, verify_jwt_token};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    fn credential() -> TicketCredential {
+        TicketCredential {
+            booking_id: Uuid::new_v4(),
+            admission_id: Uuid::new_v4(),
+            event_id: Uuid::new_v4(),
+            holder: Uuid::new_v4(),
+            ticket_id: Uuid::new_v4(),
+            seat: Some("VIP".to_string()),
+        }
+    }
+
+    fn ends_at() -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::hours(3)
+    }
+
+    #[test]
+    fn verifies_what_it_issued() {
+        let keyring = Keyring::for_tests();
+        let issued = credential();
+        let token = issue_ticket_credential(&keyring, &issued, ends_at()).unwrap();
+
+        let verified = verify_ticket_credential(&keyring, &token).unwrap();
+        assert_eq!(verified.booking_id, issued.booking_id);
+        assert_eq!(verified.admission_id, issued.admission_id);
+        assert_eq!(verified.event_id, issued.event_id);
+        assert_eq!(verified.holder, issued.holder);
+        assert_eq!(verified.ticket_id, issued.ticket_id);
+        assert_eq!(verified.seat, issued.seat);
+    }
+
+    #[test]
+    fn rejects_an_edited_payload() {
+        let keyring = Keyring::for_tests();
+        let token = issue_ticket_credential(&keyring, &credential(), ends_at()).unwrap();
+
+        // Point the credential at another booking, keeping the signature
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut claims = claims_of(&token);
+        claims["bid"] = serde_json::json!(Uuid::new_v4().to_string());
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let tampered = format!("{}.{}.{}", parts[0], payload, parts[2]);
+
+        let err = verify_ticket_credential(&keyring, &tampered).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidSignature);
+    }
+
+    // The claims of `token`, without checking it
+    fn claims_of(token: &str) -> serde_json::Value {
+        let payload = token.split('.').nth(1).unwrap();
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap()
+    }
+
+    fn sign(keyring: &Keyring, kind: TokenKind, claims: &serde_json::Value) -> String {
+        let (kid, algorithm, key) = keyring.signing_key(kind);
+        let mut header = Header::new(algorithm);
+        header.kid = Some(kid.to_string());
+        encode(&header, claims, key).unwrap()
+    }
+
+    #[test]
+    fn access_tokens_and_credentials_are_not_interchangeable() {
+        let keyring = Keyring::for_tests();
+        let access = generate_jwt_token(
+            &keyring,
+            TokenKind::Access,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Some("attendee"),
+            1,
+        )
+        .unwrap()
+        .token
+        .unwrap();
+        let ticket = issue_ticket_credential(&keyring, &credential(), ends_at()).unwrap();
+        assert!(verify_ticket_credential(&keyring, &access).is_err());
+        assert!(verify_jwt_token(&keyring, TokenKind::Access, &ticket).is_err());
+
+        // Both kinds are signed with the same key, so a token carrying every
+        // claim of both is told apart by its audience alone
+        let mut both = claims_of(&ticket);
+        for (name, value) in claims_of(&access).as_object().unwrap() {
+            both[name] = value.clone();
+        }
+        assert_eq!(both["aud"], TokenKind::Access.audience());
+        let as_access = sign(&keyring, TokenKind::Access, &both);
+        assert!(verify_jwt_token(&keyring, TokenKind::Access, &as_access).is_ok());
+        let err = verify_ticket_credential(&keyring, &as_access).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidAudience);
+
+        both["aud"] = serde_json::json!(TokenKind::Ticket.audience());
+        let as_ticket = sign(&keyring, TokenKind::Ticket, &both);
+        assert!(verify_ticket_credential(&keyring, &as_ticket).is_ok());
+        let err = verify_jwt_token(&keyring, TokenKind::Access, &as_ticket).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidAudience);
+    }
+}
@@ -1,11 +1,15 @@
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::keyring::{Keyring, TokenKind};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenDetails {
     pub token: Option<String>,
     pub user_id: Uuid,
+    pub session_id: Option<Uuid>,
+    pub role: Option<String>,
     pub expires_in: Option<i64>,
 }
 
@@ -13,11 +17,25 @@ pub struct TokenDetails {
 pub struct TokenClaims {
     pub sub: String,
     pub exp: i64,
+    pub iat: i64,
+    pub iss: String,
+    pub aud: String,
+    // Unique per token so two tokens issued in the same second never collide
+    pub jti: String,
+    // Login session the token belongs to
+    #[serde(default)]
+    pub sid: Option<String>,
+    // Account role at the time of issue, for services that only see the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
 }
 
 pub fn generate_jwt_token(
+    keyring: &Keyring,
+    kind: TokenKind,
     user_id: Uuid,
-    private_key: &str,
+    session_id: Uuid,
+    role: Option<&str>,
     time: i64,
 ) -> Result<TokenDetails, jsonwebtoken::errors::Error> {
     let now = chrono::Utc::now();
@@ -26,38 +44,60 @@ pub fn generate_jwt_token(
     let claims = TokenClaims {
         sub: user_id.to_string(),
         exp: expires_in,
+        iat: now.timestamp(),
+        iss: keyring.issuer.clone(),
+        aud: kind.audience().to_string(),
+        jti: Uuid::new_v4().to_string(),
+        sid: Some(session_id.to_string()),
+        role: role.map(str::to_string),
     };
 
-    let token = encode(
-        &Header::new(Algorithm::HS256),
-        &claims,
-        &EncodingKey::from_secret(private_key.as_bytes()),
-    )?;
+    let (kid, algorithm, key) = keyring.signing_key(kind);
+    let mut header = Header::new(algorithm);
+    header.kid = Some(kid.to_string());
+    let token = encode(&header, &claims, key)?;
 
     Ok(TokenDetails {
         token: Some(token),
         user_id,
+        session_id: Some(session_id),
+        role: claims.role,
         expires_in: Some(expires_in),
     })
 }
 
 pub fn verify_jwt_token(
-    public_key: &str,
+    keyring: &Keyring,
+    kind: TokenKind,
     token: &str,
 ) -> Result<TokenDetails, jsonwebtoken::errors::Error> {
-    let validation = Validation::new(Algorithm::HS256);
+    let header = decode_header(token)?;
+    let (algorithm, key) = keyring
+        .verification_key(kind, header.kid.as_deref())
+        .ok_or(ErrorKind::InvalidKeyFormat)?;
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&keyring.issuer]);
+    validation.set_audience(&[kind.audience()]);
+    validation.set_required_spec_claims(&["exp", "iat", "iss", "aud", "sub"]);
 
-    let decoded = decode::<TokenClaims>(
-        token,
-        &DecodingKey::from_secret(public_key.as_bytes()),
-        &validation,
-    )?;
+    let claims = decode::<TokenClaims>(token, key, &validation)?.claims;
+    // Reject tokens claiming to be issued in the future, beyond clock skew
+    if claims.iat > chrono::Utc::now().timestamp() + validation.leeway as i64 {
+        return Err(ErrorKind::ImmatureSignature.into());
+    }
+    if Uuid::parse_str(&claims.jti).is_err() {
+        return Err(ErrorKind::InvalidToken.into());
+    }
 
-    let user_id = Uuid::parse_str(&decoded.claims.sub).unwrap();
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| ErrorKind::InvalidSubject)?;
+    let session_id = claims.sid.and_then(|sid| Uuid::parse_str(&sid).ok());
 
     Ok(TokenDetails {
         token: None,
         user_id,
+        session_id,
+        role: claims.role,
         expires_in: None,
     })
 }
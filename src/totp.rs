@@ -0,0 +1,270 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use sqlx::{postgres::Postgres, Pool, Transaction};
+use uuid::Uuid;
+
+use crate::models::User;
+use crate::refresh_token::hash_token;
+
+// RFC 6238 defaults, which is what authenticator apps expect
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// Accept codes one step either side to allow for clock drift
+const DRIFT_STEPS: i64 = 1;
+const ISSUER: &str = "KriyaPass";
+const RECOVERY_CODES: usize = 10;
+const CHALLENGE_MINUTES: i64 = 5;
+const CHALLENGE_ATTEMPTS: i32 = 5;
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+/// The otpauth:// URI authenticator apps read from the enrollment QR code.
+pub fn provisioning_uri(secret: &str, email: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{email}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        issuer = ISSUER,
+        // The label is part of the URI, so characters like # and ? in an
+        // address must not end it early
+        email = urlencoding::encode(email),
+        secret = secret
+    )
+}
+
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step `code` is valid for, if it matches one newer than
+/// `last_used_step`.
+fn matching_step(secret: &str, code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    let current = Utc::now().timestamp() / STEP_SECONDS;
+    (current - DRIFT_STEPS..=current + DRIFT_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(&key, *step) == code)
+}
+
+/// Checks a TOTP code against the user's stored secret, whether or not
+/// enrollment has been confirmed yet. A code is only accepted once.
+pub async fn verify_code(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let stored = sqlx::query!(
+        "SELECT secret, last_used_step FROM user_totp WHERE user_id = $1 FOR UPDATE",
+        user_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(stored) = stored else {
+        return Ok(false);
+    };
+    let Some(step) = matching_step(&stored.secret, code, stored.last_used_step) else {
+        return Ok(false);
+    };
+    sqlx::query!(
+        "UPDATE user_totp SET last_used_step = $1 WHERE user_id = $2",
+        step,
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(true)
+}
+
+/// Spends one of the user's recovery codes.
+pub async fn redeem_recovery_code(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let redeemed = sqlx::query!(
+        "UPDATE totp_recovery_codes SET used_at = NOW()
+        WHERE code_hash = $1 AND user_id = $2 AND used_at IS NULL",
+        hash_token(&code.trim().to_lowercase()),
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(redeemed.rows_affected() == 1)
+}
+
+/// Replaces the user's recovery codes, returning the new ones in plain text.
+/// This is the only time they can be shown.
+pub async fn generate_recovery_codes(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM totp_recovery_codes WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let mut bytes = [0u8; 5];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let raw = hex::encode(bytes);
+        let code = format!("{}-{}", &raw[..5], &raw[5..]);
+        sqlx::query!(
+            "INSERT INTO totp_recovery_codes (code_hash, user_id) VALUES ($1, $2)",
+            hash_token(&code),
+            user_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+pub async fn is_enrolled(db: &Pool<Postgres>, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let enrolled = sqlx::query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL)",
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(enrolled.unwrap_or(false))
+}
+
+pub async fn role_requires_mfa(db: &Pool<Postgres>, role: &str) -> Result<bool, sqlx::Error> {
+    let required = sqlx::query_scalar!(
+        "SELECT required FROM role_mfa_policies WHERE role = $1",
+        role
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(required.unwrap_or(false))
+}
+
+/// True if the user's role requires 2FA but they have not enrolled yet.
+pub async fn enrollment_pending(db: &Pool<Postgres>, user: &User) -> Result<bool, sqlx::Error> {
+    Ok(role_requires_mfa(db, &user.role).await? && !is_enrolled(db, user.user_id).await?)
+}
+
+/// Issues the short-lived token that stands in for a password-checked login
+/// until the second factor is supplied.
+pub async fn create_challenge(db: &Pool<Postgres>, user_id: Uuid) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    let expires_at = (Utc::now() + chrono::Duration::minutes(CHALLENGE_MINUTES)).naive_utc();
+    sqlx::query!(
+        "INSERT INTO mfa_challenges (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        hash_token(&token),
+        user_id,
+        expires_at
+    )
+    .execute(db)
+    .await?;
+    Ok(token)
+}
+
+/// The user an open login challenge belongs to, if it can still be redeemed.
+pub async fn challenge_user(
+    db: &Pool<Postgres>,
+    challenge: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        "SELECT u.* FROM mfa_challenges c
+        JOIN users u ON u.user_id = c.user_id
+        WHERE c.token_hash = $1 AND c.used_at IS NULL AND c.expires_at > NOW()
+            AND c.attempts < $2",
+        hash_token(challenge),
+        CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Completes a login challenge with a TOTP or recovery code, returning the
+/// user it was issued for. Each challenge allows a handful of wrong codes.
+pub async fn redeem_challenge(
+    db: &Pool<Postgres>,
+    challenge: &str,
+    code: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM mfa_challenges
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW() AND attempts < $2
+        FOR UPDATE",
+        hash_token(challenge),
+        CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(user_id) = user_id else {
+        return Ok(None);
+    };
+
+    let passed = verify_code(&mut tx, user_id, code).await?
+        || redeem_recovery_code(&mut tx, user_id, code).await?;
+    if passed {
+        sqlx::query!(
+            "UPDATE mfa_challenges SET used_at = NOW() WHERE token_hash = $1",
+            hash_token(challenge)
+        )
+        .execute(&mut *tx)
+        .await?;
+    } else {
+        sqlx::query!(
+            "UPDATE mfa_challenges SET attempts = attempts + 1 WHERE token_hash = $1",
+            hash_token(challenge)
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(passed.then_some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provisioning_uri_escapes_the_email() {
+        let secret = generate_secret();
+        let email = "a#b?c%d&e f@example.com";
+        let uri = reqwest::Url::parse(&provisioning_uri(&secret, email)).unwrap();
+
+        let label = uri.path().trim_start_matches('/');
+        assert_eq!(
+            urlencoding::decode(label).unwrap(),
+            format!("{}:{}", ISSUER, email)
+        );
+        assert_eq!(uri.fragment(), None);
+        let query: Vec<(String, String)> = uri.query_pairs().into_owned().collect();
+        assert!(query.contains(&("secret".to_string(), secret)));
+        assert!(query.contains(&("issuer".to_string(), ISSUER.to_string())));
+    }
+}